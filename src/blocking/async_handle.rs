@@ -0,0 +1,263 @@
+//! Async handle for controlling a blocking StateMachine
+//!
+//! An [`AsyncHandle`] exposes the control and query methods of a blocking
+//! [`StateMachineHandle`] as `async fn`s, so async code can drive a machine whose
+//! handlers are synchronous. Waits, such as for a state or for the run loop to exit,
+//! are awaited without blocking the executor, and stepping runs the handlers on a
+//! worker thread shared by the handle and its clones. It does not depend on any
+//! particular runtime.
+//!
+//! ```rust
+//! use autostatemachine::blocking::{StateMachineBuilder, StateMachineContext};
+//! # async fn run() {
+//! fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let client = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build();
+//! let handle = client.async_handle();
+//! handle.run().await.unwrap();
+//! handle.pause().await;
+//! handle.stop().await;
+//! # }
+//! ```
+use std::{sync::Arc, time::Duration};
+
+use crate::blocking::context::{StateMachineContext, StopMode};
+use crate::blocking::handle::StateMachineHandle;
+use crate::error::Error;
+use crate::event::MachineEvent;
+use crate::handle::Sleep;
+use crate::history::History;
+use crate::runtime::{unblock, TimerThread, Worker};
+#[cfg(feature = "serde")]
+use crate::snapshot::MachineSnapshot;
+use crate::stats::MachineStats;
+use crate::transition::Transition;
+
+/// Cloneable handle exposing the methods of a blocking [`StateMachineHandle`] as
+/// `async fn`s
+pub struct AsyncHandle<S: 'static> {
+    handle: StateMachineHandle<S>,
+    /// Runs the handlers of `step` and its siblings, which block
+    worker: Arc<Worker>,
+}
+
+impl<S: 'static> Clone for AsyncHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            worker: self.worker.clone(),
+        }
+    }
+}
+
+impl<S> AsyncHandle<S>
+where
+    S: Send + 'static,
+{
+    pub(crate) fn new(handle: StateMachineHandle<S>) -> Self {
+        Self {
+            handle,
+            worker: Arc::default(),
+        }
+    }
+    /// The blocking handle this was created from
+    pub fn handle(&self) -> StateMachineHandle<S> {
+        self.handle.clone()
+    }
+    pub async fn get_context(&self) -> StateMachineContext {
+        self.handle.get_context()
+    }
+    pub async fn get_history(&self) -> History {
+        self.handle.get_history()
+    }
+    pub async fn get_stats(&self) -> MachineStats {
+        self.handle.get_stats()
+    }
+    pub async fn record_external(&self, name: &str, data: &str) {
+        self.handle.record_external(name, data)
+    }
+    #[cfg(feature = "serde")]
+    pub async fn snapshot(&self) -> MachineSnapshot<S> {
+        self.handle.snapshot()
+    }
+    /// Waits while a tick is in progress. See [`StateMachineHandle::snapshot_with_context`].
+    #[cfg(feature = "serde")]
+    pub async fn snapshot_with_context(&self) -> MachineSnapshot<S>
+    where
+        S: Clone,
+    {
+        self.handle.shared.snapshot_with_context().await
+    }
+    pub fn get_tick_rate(&self) -> &Duration {
+        self.handle.get_tick_rate()
+    }
+    pub fn is_running(&self) -> bool {
+        self.handle.is_running()
+    }
+    /// Stream every [`MachineEvent`] from now on. See [`StateMachineHandle::subscribe`].
+    pub fn subscribe(&self) -> futures::channel::mpsc::Receiver<MachineEvent> {
+        self.subscribe_with_capacity(crate::event::DEFAULT_CAPACITY)
+    }
+    /// Stream every [`MachineEvent`] from now on, buffering up to `capacity` of them (at
+    /// least one) before the subscriber lags
+    pub fn subscribe_with_capacity(
+        &self,
+        capacity: usize,
+    ) -> futures::channel::mpsc::Receiver<MachineEvent> {
+        self.handle.shared.subscribe(capacity)
+    }
+    /// Start the run loop on a new thread. See [`StateMachineHandle::run`].
+    pub async fn run(&self) -> Result<(), Error> {
+        let guard = self.handle.shared.claim_loop(&Sleep::Async(None)).await?;
+        StateMachineHandle::spawn_loop(guard);
+        Ok(())
+    }
+    pub async fn pause(&self) {
+        self.handle.pause()
+    }
+    pub async fn resume(&self) {
+        self.handle.resume()
+    }
+    pub async fn step(&self) -> Result<Transition, Error> {
+        let handle = self.handle.clone();
+        unblock(&*self.worker, move || handle.step()).await
+    }
+    pub async fn run_ticks(&self, n: usize) -> Result<Vec<Transition>, Error> {
+        let handle = self.handle.clone();
+        unblock(&*self.worker, move || handle.run_ticks(n)).await
+    }
+    pub async fn run_until<P>(&self, predicate: P) -> Result<Vec<Transition>, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool + Send + 'static,
+    {
+        let handle = self.handle.clone();
+        unblock(&*self.worker, move || handle.run_until(predicate)).await
+    }
+    /// Wait until the machine is in `state`. See [`StateMachineHandle::wait_for_state`].
+    pub async fn wait_for_state(
+        &self,
+        state: &str,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error> {
+        self.wait_until(|context| context.is_in(state), timeout)
+            .await
+    }
+    /// Wait until `predicate` holds for the live context. See
+    /// [`StateMachineHandle::wait_until`].
+    pub async fn wait_until<P>(
+        &self,
+        predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        let sleep = Sleep::Async(Some(Arc::new(TimerThread)));
+        self.handle
+            .shared
+            .wait_until(&sleep, predicate, timeout)
+            .await
+    }
+    /// Stop the machine and wait until its run loop has exited. See
+    /// [`StateMachineHandle::stop`].
+    pub async fn stop(&self) {
+        self.stop_with(self.handle.shared.stop_mode).await
+    }
+    pub async fn stop_with(&self, mode: StopMode) {
+        self.handle
+            .shared
+            .stop_with(&Sleep::Async(None), mode)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::builder::StateMachineBuilder;
+
+    fn test1(_: StateMachineContext) -> String {
+        "test2".to_string()
+    }
+    fn test2(_: StateMachineContext) -> String {
+        "test1".to_string()
+    }
+    #[test]
+    fn test_async_handle() {
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        let handle = client.async_handle();
+        futures::executor::block_on(async {
+            handle.run().await.unwrap();
+            assert_eq!(handle.run().await, Err(Error::AlreadyRunning));
+            handle
+                .wait_for_state("test2", Some(Duration::from_secs(1)))
+                .await
+                .unwrap();
+            handle.pause().await;
+            assert_eq!(handle.step().await.unwrap().to, "test1");
+            assert_eq!(handle.run_ticks(2).await.unwrap().len(), 2);
+            assert_eq!(handle.get_context().await.current_state, "test1");
+            handle.stop().await;
+            assert!(!handle.is_running());
+            assert_eq!(handle.get_context().await.current_state, "test1");
+        });
+    }
+    #[test]
+    fn test_async_subscribe() {
+        use futures::StreamExt;
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        let handle = client.async_handle();
+        futures::executor::block_on(async {
+            let mut events = handle.subscribe();
+            let mut lagging = handle.subscribe_with_capacity(1);
+            handle.run().await.unwrap();
+            handle.pause().await;
+            handle.stop().await;
+            assert_eq!(events.next().await, Some(MachineEvent::Started));
+            assert_eq!(lagging.next().await, Some(MachineEvent::Started));
+            // Reported once there is room again
+            handle.run().await.unwrap();
+            assert!(matches!(
+                lagging.next().await,
+                Some(MachineEvent::Lagged(missed)) if missed >= 2
+            ));
+            handle.stop().await;
+        });
+    }
+    #[test]
+    fn test_async_wait() {
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .tick_rate(Duration::from_millis(1))
+            .build();
+        let handle = client.async_handle();
+        futures::executor::block_on(async {
+            let timeout = Some(Duration::from_millis(20));
+            assert!(matches!(
+                handle.wait_for_state("test2", timeout).await,
+                Err(Error::Timeout)
+            ));
+            handle.run().await.unwrap();
+            let context = handle.wait_for_state("test2", timeout).await.unwrap();
+            assert_eq!(context.current_state, "test2");
+            handle.stop().await;
+            assert!(!handle.is_running());
+        });
+    }
+}
@@ -21,29 +21,28 @@
 //!     .tick_rate(Duration::from_millis(100))
 //!     .build();
 //! ```
-use std::{collections::HashMap, time::Duration};
-
 use crate::blocking::callback::IntoCallback;
+use crate::blocking::step::StepMachine;
 use crate::blocking::StateMachine;
+use crate::builder::shared_setters;
+use crate::flavor::Blocking;
+use crate::handle::MachineParts;
 
-use crate::blocking::callback::{Callback, StoredCallback};
+use crate::blocking::callback::Callback;
 /// Builder for StateMachine
-pub struct StateMachineBuilder<S> {
-    handlers: HashMap<String, StoredCallback<S>>,
-    tick_rate: Duration,
-    initial_state: Option<String>,
-    user_context: S,
+pub struct StateMachineBuilder<S: 'static> {
+    parts: MachineParts<S, Blocking>,
 }
 
 impl<S> StateMachineBuilder<S>
 where
-    S: Clone + Send + Sync,
+    S: Send + 'static,
 {
     /// Create a new StateMachineBuilder
     ///
     /// # Arguments
     /// * `user_context` - The user context to be passed to the callbacks.
-    /// Intended to act as some sort of state you can use in your callbacks.
+    ///   Intended to act as some sort of state you can use in your callbacks.
     ///
     /// # Example
     /// ```rust no_run
@@ -51,21 +50,20 @@ where
     /// use std::sync::{Arc, Mutex};
     /// // Pass it in () if you don't care about user_context
     /// let client = StateMachineBuilder::new(()).build();
-    /// // If you want to modify the user_context in your handlers,
-    /// // use Arc<Mutex<T>> or Arc<RwLock<T>> to wrap your user_context
-    /// // Then, the updates you make in your handlers will also be reflected
-    /// // outside of the handlers
+    /// // Handlers taking `&mut S` modify the machine's own user_context.
+    /// // If extractor handlers should modify it too, use Arc<Mutex<T>> or
+    /// // Arc<RwLock<T>> to wrap your user_context, as `State` hands out clones
     /// let client = StateMachineBuilder::new(Arc::new(Mutex::new(0))).build();
     /// ```
     pub fn new(user_context: S) -> Self {
         Self {
-            handlers: HashMap::new(),
-            tick_rate: Duration::from_millis(50),
-            initial_state: None,
-            user_context,
+            parts: MachineParts::<S, Blocking>::new(user_context),
         }
     }
     /// Add a state to the StateMachine
+    ///
+    /// `f` either takes extractors, or borrows the context and the user context as
+    /// `fn(&StateMachineContext, &mut S) -> String`, in which case nothing is cloned for it.
     /// # Arguments
     /// * `name` - The name of the state
     /// * `f` - The callback to be called when the state is active
@@ -86,28 +84,71 @@ where
         name: String,
         f: impl IntoCallback<I, S, Callback = C>,
     ) -> Self {
-        self.handlers.insert(name, Box::new(f.into_callback()));
+        self.parts
+            .handlers
+            .insert(name, Box::new(f.into_callback()));
+        self
+    }
+    /// Add a hook that runs once when the machine is stopped, after the last handler has
+    /// returned and before `stop()` resolves. Hooks take the same extractors as state
+    /// callbacks and run in the order they were added.
+    /// # Example
+    /// ```rust
+    /// use autostatemachine::blocking::{StateMachineBuilder, StateMachineContext};
+    /// fn idle(_: StateMachineContext) -> String {
+    ///   "idle".to_string()
+    /// }
+    /// fn cleanup(context: StateMachineContext) {
+    ///   println!("stopped in {}", context.current_state);
+    /// }
+    /// let client = StateMachineBuilder::new(())
+    ///  .add_state("idle".to_string(), idle)
+    ///  .on_stop(cleanup)
+    ///  .initial_state("idle".to_string())
+    ///  .build();
+    ///  ```
+    pub fn on_stop<I, C: Callback<S, ()> + 'static>(
+        mut self,
+        f: impl IntoCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        self.parts.on_stop.push(Box::new(f.into_callback()));
         self
     }
-    pub fn tick_rate(mut self, tick_rate: Duration) -> Self {
-        self.tick_rate = tick_rate;
+    /// Add a hook that runs whenever a transition enters `state`, after the handler that
+    /// made it. Hooks take the same extractors as state callbacks and see the context
+    /// from before the transition. Entering a compound state runs the hooks of its
+    /// parents first, and a state returning its own name is not entered again. The
+    /// states a machine starts in are entered before the handlers of its first tick.
+    pub fn on_enter<I, C: Callback<S, ()> + 'static>(
+        mut self,
+        state: &str,
+        f: impl IntoCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        let hooks = self.parts.hooks.enter.entry(state.to_string()).or_default();
+        hooks.push(Box::new(f.into_callback()));
         self
     }
-    pub fn initial_state(mut self, initial_state: String) -> Self {
-        self.initial_state = Some(initial_state);
+    /// Add a hook that runs whenever a transition leaves `state`, before the hooks of
+    /// the states it enters. Leaving a compound state runs the hooks of its children
+    /// first. A stopping run loop leaves the states it is in before the `on_stop` hooks.
+    pub fn on_exit<I, C: Callback<S, ()> + 'static>(
+        mut self,
+        state: &str,
+        f: impl IntoCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        let hooks = self.parts.hooks.exit.entry(state.to_string()).or_default();
+        hooks.push(Box::new(f.into_callback()));
         self
     }
+    shared_setters!();
     pub fn build(self) -> StateMachine<S> {
-        if self.handlers.is_empty() {
-            panic!("No states added");
-        }
-        let initial_state = self.initial_state.expect("Initial state not set");
-        StateMachine::new(
-            self.handlers,
-            self.tick_rate,
-            initial_state,
-            self.user_context,
-        )
+        StateMachine::from_config(self.parts.into_config())
+    }
+    /// Build a [`StepMachine`] that ticks only when its `step()` is called, for
+    /// embedding in a loop you already own. No thread is spawned and the tick rate is
+    /// only reported to handlers, never slept on.
+    pub fn build_step_machine(self) -> StepMachine<S> {
+        StepMachine::new(self.parts.into_config())
     }
 }
 #[cfg(test)]
@@ -121,6 +162,7 @@ mod tests {
         "test".to_string()
     }
     use crate::{blocking::context::StateMachineContext, blocking::extractor::TickRate};
+    use std::time::Duration;
 
     use super::*;
     #[test]
@@ -133,7 +175,7 @@ mod tests {
         assert_eq!(client.get_context().current_state, "test");
         assert_eq!(client.get_tick_rate(), &Duration::from_millis(50));
         assert_eq!(client.get_user_context(), &());
-        assert_eq!(client.handlers.len(), 2);
+        assert_eq!(client.handle.shared.handlers.len(), 2);
     }
     #[test]
     #[should_panic]
@@ -1,44 +1,42 @@
-use crate::blocking::context::StateMachineContext;
-use crate::blocking::extractor::FromContext;
-pub trait IntoCallback<Input, S> {
-    type Callback: Callback<S>;
+use crate::callback::{Borrowed, Wrapper};
+use crate::context::StateMachineContext;
+use crate::extractor::FromContext;
+use crate::state::Target;
+pub trait IntoCallback<Input, S, O = Target> {
+    type Callback: Callback<S, O>;
 
     fn into_callback(self) -> Self::Callback;
 }
-/// Wrapper type for erasure. T is the generic function arguments for F
-pub struct Wrapper<T, F> {
-    pub f: F,
-    pub marker: std::marker::PhantomData<T>,
-}
 
-pub trait Callback<S>: Send + Sync {
-    fn call(&self, context: &StateMachineContext, s: &mut S) -> String;
+/// A state handler or lifecycle hook. `O` is what the callback returns: the next
+/// state for handlers, `()` for hooks.
+pub trait Callback<S, O = Target>: Send + Sync {
+    fn call(&self, context: &StateMachineContext, s: &mut S) -> O;
 }
-pub type StoredCallback<S> = Box<dyn Callback<S>>;
+pub type StoredCallback<S, O = Target> = Box<dyn Callback<S, O>>;
 macro_rules! impl_callback {
     (
         $($(
                 $params:ident
         ),+)?
     ) => {
-        impl<F: Fn($($($params),+)?)->String + Send + Sync $(, $($params: 'static + FromContext<S> + Send + Sync),+ )?, S> Callback<S> for Wrapper<( $($($params,)+)? ), F> {
-
-            fn call(&self, context: &StateMachineContext, s: &mut S) -> String {
-                (self.f)($($($params::from_context(context, s)),+)?)
+        impl<F, R, $($($params,)+)? S, O> Callback<S, O> for Wrapper<( $($($params,)+)? ), F>
+        where
+            F: Fn($($($params),+)?) -> R + Send + Sync,
+            R: Into<O>,
+            $($($params: 'static + FromContext<S> + Send + Sync,)+)?
+        {
+            #[allow(unused_variables)]
+            fn call(&self, context: &StateMachineContext, s: &mut S) -> O {
+                (self.f)($($($params::from_context(context, s)),+)?).into()
             }
         }
-    }
-}
-impl_callback!(T1);
-impl_callback!(T1, T2);
-
-macro_rules! impl_into_callback {
-    (
-        $($(
-                $params:ident
-        ),+)?
-    ) => {
-        impl<F: Fn($($($params),+)?)->String + Send + Sync $(, $($params: 'static + FromContext<S> + Send + Sync),+ )?, S> IntoCallback<( $($($params,)+)? ), S> for F {
+        impl<F, R, $($($params,)+)? S, O> IntoCallback<( $($($params,)+)? ), S, O> for F
+        where
+            F: Fn($($($params),+)?) -> R + Send + Sync,
+            R: Into<O>,
+            $($($params: 'static + FromContext<S> + Send + Sync,)+)?
+        {
             type Callback = Wrapper<( $($($params,)+)? ), Self>;
 
             fn into_callback(self) -> Self::Callback {
@@ -50,9 +48,33 @@ macro_rules! impl_into_callback {
         }
     }
 }
+impl_callback!();
+impl_callback!(T1);
+impl_callback!(T1, T2);
+impl_callback!(T1, T2, T3);
+impl_callback!(T1, T2, T3, T4);
+
+// Handlers that borrow the context and the user context instead of using extractors
+impl<F, R, S, O> Callback<S, O> for Wrapper<Borrowed, F>
+where
+    F: Fn(&StateMachineContext, &mut S) -> R + Send + Sync,
+    R: Into<O>,
+{
+    fn call(&self, context: &StateMachineContext, s: &mut S) -> O {
+        (self.f)(context, s).into()
+    }
+}
+impl<F, R, S, O> IntoCallback<Borrowed, S, O> for F
+where
+    F: Fn(&StateMachineContext, &mut S) -> R + Send + Sync,
+    R: Into<O>,
+{
+    type Callback = Wrapper<Borrowed, Self>;
 
-// impl_into_callback!();
-impl_into_callback!(T1);
-impl_into_callback!(T1, T2);
-// impl_into_callback!(T1, T2, T3);
-// impl_into_callback!(T1, T2, T3, T4);
+    fn into_callback(self) -> Self::Callback {
+        Wrapper {
+            f: self,
+            marker: Default::default(),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Shareable handle for controlling a StateMachine
+//!
+//! A [`StateMachineHandle`] is cheap to clone and can be moved into as many threads as
+//! needed, so a machine can be controlled from several places without wrapping the
+//! machine in a mutex.
+//!
+//! ```rust
+//! use autostatemachine::blocking::{StateMachineBuilder, StateMachineContext};
+//! fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let client = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build();
+//! let handle = client.handle();
+//! std::thread::spawn(move || handle.pause()).join().unwrap();
+//! ```
+use std::{
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+
+use crate::blocking::async_handle::AsyncHandle;
+use crate::blocking::block_on;
+use crate::blocking::context::{LifeCycle, StateMachineContext, StopMode, UserContext};
+use crate::error::Error;
+use crate::event::MachineEvent;
+use crate::flavor::Blocking;
+use crate::handle::{run_loop, LoopGuard, Shared, Sleep};
+use crate::history::History;
+#[cfg(feature = "serde")]
+use crate::snapshot::MachineSnapshot;
+use crate::stats::MachineStats;
+use crate::transition::Transition;
+
+/// Everything the builder collects to construct a machine
+pub(crate) type MachineConfig<S> = crate::handle::MachineConfig<S, Blocking>;
+
+/// Cloneable handle exposing the control and query methods of a StateMachine
+pub struct StateMachineHandle<S: 'static> {
+    pub(crate) shared: Arc<Shared<S, Blocking>>,
+}
+
+impl<S: 'static> Clone for StateMachineHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S> StateMachineHandle<S>
+where
+    S: Send + 'static,
+{
+    pub(crate) fn new(config: MachineConfig<S>) -> Self {
+        Self {
+            shared: Arc::new(Shared::new(config, None)),
+        }
+    }
+    pub fn get_context(&self) -> StateMachineContext {
+        StateMachineContext::clone(&self.shared.lock().context)
+    }
+    /// The most recent transitions, oldest first
+    pub fn get_history(&self) -> History {
+        self.shared.lock().context.history.clone()
+    }
+    /// Statistics of every tick so far. See the [`stats`](crate::stats) module.
+    pub fn get_stats(&self) -> MachineStats {
+        self.shared.lock().stats.snapshot()
+    }
+    /// Publish that `name` happened outside the machine, such as a message that its
+    /// handlers will act on, so journals record it for replay. See the
+    /// [`journal`](crate::journal) module.
+    pub fn record_external(&self, name: &str, data: &str) {
+        self.shared.record_external(name, data);
+    }
+    /// Where the machine is, without its user context. See the
+    /// [`snapshot`](crate::snapshot) module.
+    #[cfg(feature = "serde")]
+    pub fn snapshot(&self) -> MachineSnapshot<S> {
+        self.shared.lock().snapshot()
+    }
+    /// Where the machine is, with a clone of its user context. Blocks while a tick is
+    /// in progress, so both are taken at the same point.
+    #[cfg(feature = "serde")]
+    pub fn snapshot_with_context(&self) -> MachineSnapshot<S>
+    where
+        S: Clone,
+    {
+        block_on(self.shared.snapshot_with_context())
+    }
+    /// Lock the user context, blocking while a tick is in progress. Must not be called
+    /// from inside a handler or hook of the same machine.
+    pub fn get_user_context(&self) -> UserContext<'_, S> {
+        UserContext(block_on(self.shared.user_context.lock()))
+    }
+    pub fn get_tick_rate(&self) -> &Duration {
+        &self.shared.tick_rate
+    }
+    /// Receive every [`MachineEvent`] from now on, buffering up to
+    /// [`DEFAULT_CAPACITY`](crate::event::DEFAULT_CAPACITY) of them. See the
+    /// [`event`](crate::event) module.
+    pub fn subscribe(&self) -> Receiver<MachineEvent> {
+        self.subscribe_with_capacity(crate::event::DEFAULT_CAPACITY)
+    }
+    /// Receive every [`MachineEvent`] from now on, buffering up to `capacity` of them
+    /// (at least one) before the subscriber lags
+    pub fn subscribe_with_capacity(&self, capacity: usize) -> Receiver<MachineEvent> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(capacity.max(1));
+        self.shared.lock().subscribe(tx);
+        rx
+    }
+    /// Whether a run loop is currently active, including one that has been told to
+    /// stop but has not exited yet
+    pub fn is_running(&self) -> bool {
+        self.shared.lock().loop_active
+    }
+    /// Pause a running machine. Has no effect if the machine is stopped.
+    pub fn pause(&self) {
+        self.shared.set_life_cycle(LifeCycle::Paused);
+    }
+    /// Resume a paused machine. Has no effect if the machine is stopped.
+    pub fn resume(&self) {
+        self.shared.set_life_cycle(LifeCycle::Running);
+    }
+    /// Stop the machine using the builder's [`StopMode`] (`StopMode::Reset` unless
+    /// configured otherwise). See [`stop_with`](Self::stop_with).
+    pub fn stop(&self) {
+        self.stop_with(self.shared.stop_mode)
+    }
+    /// Stop the machine and block until the run loop has exited and every `on_stop`
+    /// hook has run. `mode` decides whether `current_state` is reset to the initial
+    /// state or preserved for the next `run()`.
+    ///
+    /// Must not be called from inside a handler or hook of the same machine, as the
+    /// loop cannot exit until that handler returns.
+    pub fn stop_with(&self, mode: StopMode) {
+        block_on(self.shared.stop_with(&Sleep::Thread, mode))
+    }
+    /// Run exactly one tick of a paused machine and return the transition it made.
+    ///
+    /// Returns [`Error::NotPaused`] unless the machine is running and paused,
+    /// [`Error::UnknownState`] if the current state has no handler, and
+    /// [`Error::OutsideRegion`] if a region of a parallel state returned a state outside it.
+    pub fn step(&self) -> Result<Transition, Error> {
+        block_on(self.shared.step())
+    }
+    /// Run `n` ticks of a paused machine, one after another, returning every transition.
+    pub fn run_ticks(&self, n: usize) -> Result<Vec<Transition>, Error> {
+        block_on(self.shared.run_ticks(n))
+    }
+    /// Run ticks of a paused machine until `predicate` holds for its context, returning
+    /// every transition made. Runs no ticks if the predicate already holds.
+    pub fn run_until<F>(&self, predicate: F) -> Result<Vec<Transition>, Error>
+    where
+        F: FnMut(&StateMachineContext) -> bool,
+    {
+        block_on(self.shared.run_until(predicate))
+    }
+    /// Block until the machine is in `state`. See [`wait_until`](Self::wait_until).
+    pub fn wait_for_state(
+        &self,
+        state: &str,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error> {
+        self.wait_until(|context| context.is_in(state), timeout)
+    }
+    /// Block until `predicate` holds for the live context and return that context. The
+    /// predicate is checked again whenever the context changes, so this returns as soon
+    /// as it holds, and straight away if it already does.
+    ///
+    /// Returns [`Error::Timeout`] if `timeout` passes first.
+    pub fn wait_until<P>(
+        &self,
+        predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        block_on(self.shared.wait_until(&Sleep::Thread, predicate, timeout))
+    }
+    /// See [`Shared::request_stop`](crate::handle::Shared::request_stop)
+    pub(crate) fn request_stop(&self, mode: StopMode) -> bool {
+        self.shared.request_stop(mode)
+    }
+    /// Start the run loop on a new thread.
+    ///
+    /// Only one run loop may be active at a time: if the machine is running or paused
+    /// this returns [`Error::AlreadyRunning`]. If it has been stopped but the previous
+    /// loop has not exited yet, this blocks until that loop exits before starting again.
+    pub fn run(&self) -> Result<(), Error> {
+        let guard = block_on(self.shared.claim_loop(&Sleep::Thread))?;
+        Self::spawn_loop(guard);
+        Ok(())
+    }
+    /// Drive the run loop claimed by `guard` on a new thread
+    pub(crate) fn spawn_loop(guard: LoopGuard<S, Blocking>) {
+        std::thread::spawn(move || block_on(run_loop(guard, Sleep::Thread)));
+    }
+    /// Get a handle exposing the same methods as `async fn`s. See [`AsyncHandle`].
+    pub fn async_handle(&self) -> AsyncHandle<S> {
+        AsyncHandle::new(self.clone())
+    }
+}
@@ -16,11 +16,11 @@
 //!
 //! ## Quick Start
 //!
-//! Add `mycrate` to your `Cargo.toml`:
+//! Add `autostatemachine` to your `Cargo.toml`:
 //!
 //! ```toml
 //! [dependencies]
-//! mycrate = "0.1.0"
+//! autostatemachine = "0.1.0"
 //! ```
 //!
 //! ### Example
@@ -36,13 +36,13 @@
 //!     "init".to_string()
 //! }
 //!
-//! let mut client = StateMachineBuilder::new(())
+//! let client = StateMachineBuilder::new(())
 //!     .add_state("init".to_string(), sample_callback)
 //!     .initial_state("init".to_string())
 //!     .tick_rate(Duration::from_secs(1))
 //!     .build();
 //!
-//! client.run();
+//! client.run().unwrap();
 //! // The client is now running, transitioning from "init" to "next_state"
 //! // according to the logic you've defined.
 //! std::thread::sleep(Duration::from_millis(50));
@@ -51,40 +51,106 @@
 //!
 //! ## Control Flow Methods
 //!
-//! - `run()`: Start the client's execution, allowing state transitions to occur.
+//! - `run()`: Start the client's execution, allowing state transitions to occur. Only one run
+//!   loop may be active at a time.
 //! - `pause()`: Pause the execution, freezing the current state.
 //! - `resume()`: Resume execution from the current state.
-//! - `stop()`: Stop execution, resetting to the initial state.
+//! - `step()`, `run_ticks(n)`, `run_until(predicate)`: Advance a paused client tick by tick.
+//! - `stop()`: Stop execution and wait for the loop to exit, resetting to the initial state
+//!   (or preserving the current one with `stop_with(StopMode::Preserve)`).
+//! - `handle()`: Get a cloneable `StateMachineHandle` exposing all of the above by `&self`.
+//! - `async_handle()`: Get an `AsyncHandle` exposing the same methods as `async fn`s.
 //!
 //! This crate aims to simplify the creation of automated, state-driven systems with minimal boilerplate
 //! and high flexibility. For more detailed documentation and advanced usage, please refer to the specific
 //! module and method documentation within the crate.
+mod async_handle;
 pub mod builder;
-mod callback;
+pub(crate) mod callback;
 pub mod context;
 pub mod extractor;
+mod handle;
+mod step;
+pub use crate::error::Error;
+pub use crate::event::{ExternalEvent, MachineEvent, TransitionEvent};
+#[cfg(feature = "serde")]
+pub use crate::snapshot::MachineSnapshot;
+pub use crate::state::{StateId, StateName, Target};
+pub use crate::stats::MachineStats;
+pub use crate::transition::{Transition, TransitionOutcome};
+pub use async_handle::AsyncHandle;
 pub use builder::StateMachineBuilder;
-pub use context::StateMachineContext;
+pub use context::{StateMachineContext, StopMode, UserContext};
+pub use handle::StateMachineHandle;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    future::Future,
+    pin::pin,
+    sync::{mpsc::Receiver, Arc},
+    task::{Context, Poll, Wake, Waker},
+    thread::Thread,
     time::Duration,
 };
+pub use step::StepMachine;
+
+use handle::MachineConfig;
 
 use callback::StoredCallback;
 
+/// Wakes a thread parked in [`block_on`]
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+thread_local! {
+    /// The waker of the current thread, made once so blocking calls do not allocate
+    static WAKER: Waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+}
+
+/// Drive `future` on the calling thread, parking it while the future is pending. The
+/// blocking flavor's futures only wait on the user context lock, and unlike
+/// `futures::executor::block_on` this may be nested in a handler that drives another
+/// machine.
+pub(crate) fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let waker = WAKER.with(Waker::clone);
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut context) {
+            return value;
+        }
+        std::thread::park();
+    }
+}
+
+/// A state machine and the owner of its run loop.
+///
+/// Dropping the machine tells its run loop to stop (using the configured [`StopMode`])
+/// without waiting for it. Use [`detach`](Self::detach) to keep the loop running.
 pub struct StateMachine<S>
 where
-    S: Clone + Send + Sync + 'static,
+    S: Send + 'static,
 {
-    handlers: Arc<HashMap<String, StoredCallback<S>>>,
-    tick_rate: Duration,
-    context: Arc<Mutex<StateMachineContext>>,
-    user_context: S,
+    handle: StateMachineHandle<S>,
+    detached: bool,
+}
+impl<S> Drop for StateMachine<S>
+where
+    S: Send + 'static,
+{
+    fn drop(&mut self) {
+        if !self.detached {
+            self.handle.request_stop(self.handle.shared.stop_mode);
+        }
+    }
 }
 impl<S> StateMachine<S>
 where
-    S: Clone + Send + Sync,
+    S: Send + 'static,
 {
     pub fn new(
         handlers: HashMap<String, StoredCallback<S>>,
@@ -92,70 +158,133 @@ where
         initial_state: String,
         user_context: S,
     ) -> Self {
-        Self {
-            handlers: Arc::new(handlers),
+        Self::from_config(MachineConfig::new(
+            crate::state::States::new(handlers),
+            Vec::new(),
             tick_rate,
-            context: Arc::new(Mutex::new(StateMachineContext {
-                tick_rate,
-                current_state: initial_state.clone(),
-                initial_state,
-                life_cycle: context::LifeCycle::Stopped,
-            })),
+            &initial_state,
+            StopMode::default(),
             user_context,
+        ))
+    }
+    pub(crate) fn from_config(config: MachineConfig<S>) -> Self {
+        Self {
+            handle: StateMachineHandle::new(config),
+            detached: false,
         }
     }
+    /// Give up ownership of the run loop, so it keeps running after the machine is
+    /// gone. The returned handle is then the only way to stop it.
+    pub fn detach(mut self) -> StateMachineHandle<S> {
+        self.detached = true;
+        self.handle.clone()
+    }
+    /// Get a cloneable handle that can control this machine from other threads
+    pub fn handle(&self) -> StateMachineHandle<S> {
+        self.handle.clone()
+    }
     pub fn get_context(&self) -> StateMachineContext {
-        self.context.lock().unwrap().clone()
+        self.handle.get_context()
+    }
+    pub fn get_history(&self) -> extractor::History {
+        self.handle.get_history()
     }
-    pub fn get_user_context(&self) -> &S {
-        &self.user_context
+    pub fn get_stats(&self) -> MachineStats {
+        self.handle.get_stats()
+    }
+    /// See [`StateMachineHandle::record_external`]
+    pub fn record_external(&self, name: &str, data: &str) {
+        self.handle.record_external(name, data)
+    }
+    /// See [`StateMachineHandle::snapshot`]
+    #[cfg(feature = "serde")]
+    pub fn snapshot(&self) -> MachineSnapshot<S> {
+        self.handle.snapshot()
+    }
+    /// See [`StateMachineHandle::snapshot_with_context`]
+    #[cfg(feature = "serde")]
+    pub fn snapshot_with_context(&self) -> MachineSnapshot<S>
+    where
+        S: Clone,
+    {
+        self.handle.snapshot_with_context()
+    }
+    pub fn get_user_context(&self) -> UserContext<'_, S> {
+        self.handle.get_user_context()
     }
     pub fn get_tick_rate(&self) -> &Duration {
-        &self.tick_rate
-    }
-    pub fn pause(&mut self) {
-        self.context.lock().unwrap().life_cycle = context::LifeCycle::Paused;
-    }
-    pub fn resume(&mut self) {
-        self.context.lock().unwrap().life_cycle = context::LifeCycle::Running;
-    }
-    pub fn stop(&mut self) {
-        self.context.lock().unwrap().life_cycle = context::LifeCycle::Stopped;
-    }
-    pub fn run(&mut self) {
-        self.context.lock().unwrap().life_cycle = context::LifeCycle::Running;
-        let context = self.context.clone();
-        let user_context = self.user_context.clone();
-        let handlers = self.handlers.clone();
-        std::thread::spawn(move || loop {
-            let mut context_guard = context.lock().unwrap();
-            let tick_rate = context_guard.tick_rate;
-            match context_guard.life_cycle {
-                context::LifeCycle::Paused => {
-                    drop(context_guard);
-                    std::thread::sleep(tick_rate);
-                }
-                context::LifeCycle::Stopped => {
-                    context_guard.life_cycle = context::LifeCycle::Stopped;
-                    context_guard.current_state = context_guard.initial_state.clone();
-                    break;
-                }
-                context::LifeCycle::Running => {
-                    let handler = handlers.get(&context_guard.current_state).unwrap();
-                    let output = handler.call(&context_guard, &mut user_context.clone());
-                    context_guard.current_state = output;
-                    drop(context_guard);
-                    std::thread::sleep(tick_rate);
-                }
-            }
-        });
+        self.handle.get_tick_rate()
+    }
+    /// Receive every [`MachineEvent`] from now on. See [`StateMachineHandle::subscribe`].
+    pub fn subscribe(&self) -> Receiver<MachineEvent> {
+        self.handle.subscribe()
+    }
+    pub fn subscribe_with_capacity(&self, capacity: usize) -> Receiver<MachineEvent> {
+        self.handle.subscribe_with_capacity(capacity)
+    }
+    pub fn is_running(&self) -> bool {
+        self.handle.is_running()
+    }
+    pub fn pause(&self) {
+        self.handle.pause()
+    }
+    pub fn resume(&self) {
+        self.handle.resume()
+    }
+    pub fn step(&self) -> Result<Transition, Error> {
+        self.handle.step()
+    }
+    pub fn run_ticks(&self, n: usize) -> Result<Vec<Transition>, Error> {
+        self.handle.run_ticks(n)
+    }
+    pub fn run_until<F>(&self, predicate: F) -> Result<Vec<Transition>, Error>
+    where
+        F: FnMut(&StateMachineContext) -> bool,
+    {
+        self.handle.run_until(predicate)
+    }
+    pub fn wait_for_state(
+        &self,
+        state: &str,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error> {
+        self.handle.wait_for_state(state, timeout)
+    }
+    pub fn wait_until<P>(
+        &self,
+        predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        self.handle.wait_until(predicate, timeout)
+    }
+    pub fn stop(&self) {
+        self.handle.stop()
+    }
+    pub fn stop_with(&self, mode: StopMode) {
+        self.handle.stop_with(mode)
+    }
+    pub fn run(&self) -> Result<(), Error> {
+        self.handle.run()
+    }
+    /// Get a handle that controls this machine from async code. See [`AsyncHandle`].
+    pub fn async_handle(&self) -> AsyncHandle<S> {
+        self.handle.async_handle()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
     use super::*;
     use crate::blocking::builder::StateMachineBuilder;
+    use crate::blocking::extractor::State;
 
     fn test1(_: StateMachineContext) -> String {
         println!("test1");
@@ -165,36 +294,321 @@ mod tests {
         println!("test2");
         "test1".to_string()
     }
-    #[test]
-    fn test_basic_run() {
-        let mut client = StateMachineBuilder::new("".to_string())
+    /// A machine bouncing between `test1` and `test2`, starting in `test1`
+    fn ping_pong<S: Send + 'static>(user_context: S) -> StateMachineBuilder<S> {
+        StateMachineBuilder::new(user_context)
             .add_state("test1".to_string(), test1)
             .add_state("test2".to_string(), test2)
             .initial_state("test1".to_string())
+    }
+    /// Start `client` and wait for its first transition, into `test2`
+    fn run_to_test2<S: Send + 'static>(client: &StateMachine<S>) {
+        client.run().unwrap();
+        client
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .unwrap();
+    }
+    #[test]
+    fn test_basic_run() {
+        let client = ping_pong("".to_string()).build();
+        client.run().unwrap();
+        let context = client
+            .wait_until(
+                |context| context.history.len() == 2,
+                Some(Duration::from_secs(1)),
+            )
+            .unwrap();
+        assert_eq!(context.current_state, "test1");
+        assert_eq!(
+            context
+                .history
+                .iter()
+                .map(|t| t.to.as_str())
+                .collect::<Vec<_>>(),
+            ["test2", "test1"]
+        );
+        client.stop();
+    }
+    #[test]
+    fn test_pause() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_millis(1))
+            .build();
+        client.run().unwrap();
+        client.wait_for_state("test2", None).unwrap();
+        client.pause();
+        // Once the tick in progress is done, no other starts while paused
+        drop(client.get_user_context());
+        let ticks = client.get_stats().ticks;
+        let paused_in = client.get_context().current_state;
+        assert_eq!(client.step().unwrap().from, paused_in);
+        assert_eq!(client.get_stats().ticks, ticks + 1);
+        client.resume();
+        client
+            .wait_until(
+                |context| context.history.last().is_some_and(|t| t.tick > ticks + 1),
+                None,
+            )
+            .unwrap();
+        client.stop();
+    }
+    #[test]
+    fn test_handle_controls_machine() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
             .build();
-        client.run();
-        std::thread::sleep(Duration::from_millis(10));
+        let handle = client.handle();
+        let remote = handle.clone();
+        std::thread::spawn(move || remote.run())
+            .join()
+            .unwrap()
+            .unwrap();
+        handle
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .unwrap();
+        let remote = handle.clone();
+        std::thread::spawn(move || remote.pause()).join().unwrap();
+        // Only a paused machine can be stepped
+        assert_eq!(client.step().unwrap().to, "test1");
+        handle.stop();
+    }
+    #[test]
+    fn test_single_run_loop() {
+        let client = ping_pong("".to_string()).build();
+        client.run().unwrap();
+        assert_eq!(client.run(), Err(Error::AlreadyRunning));
+        client.pause();
+        assert_eq!(client.run(), Err(Error::AlreadyRunning));
+        client.stop();
+        // Waits for the stopped loop to exit before starting a fresh one
+        client.run().unwrap();
+        assert!(client.is_running());
+        client.stop();
+    }
+    #[test]
+    fn test_stop_waits_for_hooks_and_resets() {
+        let stops = Arc::new(AtomicUsize::new(0));
+        let client = ping_pong(stops.clone())
+            .on_stop(|State(stops): State<Arc<AtomicUsize>>| {
+                stops.fetch_add(1, Ordering::SeqCst);
+            })
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        run_to_test2(&client);
+        client.stop();
+        assert!(!client.is_running());
+        assert_eq!(stops.load(Ordering::SeqCst), 1);
+        assert_eq!(client.get_context().current_state, "test1");
+    }
+    #[test]
+    fn test_stop_preserve() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        run_to_test2(&client);
+        client.stop_with(StopMode::Preserve);
         assert_eq!(client.get_context().current_state, "test2");
-        std::thread::sleep(Duration::from_millis(51));
+        // Picks up where it left off
+        client.run().unwrap();
+        let context = client
+            .wait_until(
+                |context| context.history.len() == 2,
+                Some(Duration::from_secs(1)),
+            )
+            .unwrap();
+        assert_eq!(context.history.last().unwrap().from, "test2");
+        client.stop();
         assert_eq!(client.get_context().current_state, "test1");
+    }
+    #[test]
+    fn test_drop_stops_loop() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        run_to_test2(&client);
+        let handle = client.handle();
+        let events = handle.subscribe();
+        drop(client);
+        // The loop exits without anyone calling stop
+        assert!(events.iter().any(|event| event == MachineEvent::Stopped));
+        assert_eq!(handle.get_context().current_state, "test1");
+    }
+    #[test]
+    fn test_detach_keeps_loop() {
+        let client = ping_pong("".to_string()).build();
+        client.run().unwrap();
+        let handle = client.detach();
+        // Still ticking after the machine is gone
+        handle
+            .wait_until(
+                |context| context.history.last().is_some_and(|t| t.tick >= 3),
+                Some(Duration::from_secs(1)),
+            )
+            .unwrap();
+        assert!(handle.is_running());
+        handle.stop();
+        assert!(!handle.is_running());
+    }
+    #[test]
+    fn test_control_is_immediate() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(3600))
+            .build();
+        client.run().unwrap();
+        // The first tick runs at once, then the loop sleeps for an hour
+        client.wait_for_state("test2", None).unwrap();
+        client.pause();
+        client.step().unwrap();
+        client.resume();
+        // Stopping interrupts the sleep, or this would not return for an hour
         client.stop();
+        assert_eq!(client.get_stats().ticks, 2);
     }
     #[test]
-    fn test_pause() {
-        let mut client = StateMachineBuilder::new("".to_string())
-            .add_state("test1".to_string(), test1)
-            .add_state("test2".to_string(), test2)
-            .initial_state("test1".to_string())
+    fn test_step_while_paused() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
             .build();
-        client.run();
-        std::thread::sleep(Duration::from_millis(10));
-        assert_eq!(client.get_context().current_state, "test2");
+        assert_eq!(client.step(), Err(Error::NotPaused));
+        client.run().unwrap();
+        assert_eq!(client.step(), Err(Error::NotPaused));
+        client
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .unwrap();
         client.pause();
-        std::thread::sleep(Duration::from_millis(51));
         assert_eq!(client.get_context().current_state, "test2");
-        client.resume();
-        std::thread::sleep(Duration::from_millis(51));
-        assert_eq!(client.get_context().current_state, "test1");
+        let transition = client.step().unwrap();
+        assert_eq!(transition.from, "test2");
+        assert_eq!(transition.to, "test1");
+        assert_eq!(client.run_ticks(3).unwrap().len(), 3);
+        assert_eq!(client.get_context().current_state, "test2");
+        let transitions = client
+            .run_until(|ctx| ctx.current_state == "test1")
+            .unwrap();
+        assert_eq!(
+            transitions,
+            vec![Transition {
+                from: "test2".into(),
+                to: "test1".into()
+            }]
+        );
+        client.stop();
+    }
+    #[test]
+    fn test_borrowing_handler() {
+        fn count(_: &StateMachineContext, ticks: &mut usize) -> String {
+            *ticks += 1;
+            "count".to_string()
+        }
+        let client = StateMachineBuilder::new(0)
+            .add_state("count".to_string(), count)
+            .on_stop(|_: &StateMachineContext, ticks: &mut usize| *ticks += 100)
+            .initial_state("count".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        client.run().unwrap();
+        client
+            .wait_until(|context| context.history.len() == 1, None)
+            .unwrap();
+        client.pause();
+        client.run_ticks(2).unwrap();
+        client.stop();
+        assert_eq!(*client.get_user_context(), 103);
+    }
+    #[test]
+    fn test_failures_are_published() {
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), |_: StateMachineContext| {
+                "missing".to_string()
+            })
+            .initial_state("test1".to_string())
+            .build();
+        let events = client.subscribe();
+        client.run().unwrap();
+        let events: Vec<_> = events.iter().take(4).collect();
+        assert_eq!(events[0], MachineEvent::Started);
+        assert!(matches!(events[1], MachineEvent::Transition(_)));
+        assert_eq!(
+            events[2..],
+            [
+                MachineEvent::Failed(Error::UnknownState("missing".to_string())),
+                MachineEvent::Stopped
+            ]
+        );
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), |_: StateMachineContext| -> String {
+                panic!("handler failed")
+            })
+            .initial_state("test1".to_string())
+            .build();
+        let events = client.subscribe();
+        client.run().unwrap();
+        let events: Vec<_> = events.iter().skip(1).take(2).collect();
+        assert_eq!(
+            events,
+            [
+                MachineEvent::Failed(Error::HandlerPanicked("test1".to_string())),
+                MachineEvent::Stopped
+            ]
+        );
+    }
+    #[test]
+    fn test_unknown_state_stops_loop() {
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), |_: StateMachineContext| {
+                "missing".to_string()
+            })
+            .initial_state("test1".to_string())
+            .stop_mode(StopMode::Preserve)
+            .build();
+        client.run().unwrap();
+        let context = client
+            .wait_until(
+                |context| matches!(context.life_cycle, context::LifeCycle::Stopped),
+                Some(Duration::from_secs(1)),
+            )
+            .unwrap();
+        assert_eq!(context.current_state, "missing");
+    }
+    #[test]
+    fn test_history_extractor() {
+        fn bounce(history: extractor::History) -> String {
+            match history.count("b", "a") {
+                0 | 1 => "b".to_string(),
+                _ => "done".to_string(),
+            }
+        }
+        let mut machine = StateMachineBuilder::new(())
+            .add_state("a".to_string(), bounce)
+            .add_state("b".to_string(), |_: StateMachineContext| "a".to_string())
+            .initial_state("a".to_string())
+            .build_step_machine();
+        let states: Vec<_> = (0..5)
+            .map(|_| match machine.step() {
+                TransitionOutcome::Transitioned(t) => t.to.to_string(),
+                TransitionOutcome::UnknownState(state) => state.to_string(),
+                TransitionOutcome::OutsideRegion(t) => t.to.to_string(),
+            })
+            .collect();
+        assert_eq!(states, ["b", "a", "b", "a", "done"]);
+        assert_eq!(machine.get_context().history.len(), 5);
+    }
+    #[test]
+    fn test_wait_for_state() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        client.run().unwrap();
+        let context = client
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(context.current_state, "test2");
+        assert_eq!(
+            client
+                .wait_for_state("test1", Some(Duration::from_millis(20)))
+                .err(),
+            Some(Error::Timeout)
+        );
         client.stop();
     }
 }
@@ -0,0 +1,119 @@
+//! Manually stepped StateMachine
+//!
+//! A [`StepMachine`] runs exactly one handler each time [`StepMachine::step`] is called.
+//! It never spawns a thread or sleeps, so it can be driven from an existing frame or
+//! event loop that decides when ticks happen. The run loop behind `StateMachine::run`
+//! drives its ticks through the same core.
+//!
+//! ```rust
+//! use autostatemachine::blocking::{StateMachineBuilder, StateMachineContext, TransitionOutcome};
+//! fn ping(_: StateMachineContext) -> String {
+//!     "pong".to_string()
+//! }
+//! fn pong(_: StateMachineContext) -> String {
+//!     "ping".to_string()
+//! }
+//! let mut machine = StateMachineBuilder::new(())
+//!     .add_state("ping".to_string(), ping)
+//!     .add_state("pong".to_string(), pong)
+//!     .initial_state("ping".to_string())
+//!     .build_step_machine();
+//! // Called once per frame
+//! if let TransitionOutcome::Transitioned(t) = machine.step() {
+//!     println!("{} -> {}", t.from, t.to);
+//! }
+//! ```
+#[cfg(feature = "journal")]
+use std::io::BufRead;
+
+use crate::blocking::block_on;
+use crate::blocking::context::StateMachineContext;
+use crate::blocking::handle::MachineConfig;
+#[cfg(feature = "journal")]
+use crate::error::Error;
+#[cfg(feature = "journal")]
+use crate::event::ExternalEvent;
+use crate::flavor::Blocking;
+#[cfg(feature = "journal")]
+use crate::journal::ReplayReport;
+use crate::stats::MachineStats;
+use crate::transition::TransitionOutcome;
+
+/// A StateMachine that only ticks when told to
+pub struct StepMachine<S: 'static> {
+    /// The async core, whose futures are ready as soon as the handlers return
+    inner: crate::step::StepMachine<S, Blocking>,
+}
+
+impl<S> StepMachine<S> {
+    pub(crate) fn new(config: MachineConfig<S>) -> Self {
+        Self {
+            inner: crate::step::StepMachine::new(config),
+        }
+    }
+    pub fn get_context(&self) -> &StateMachineContext {
+        self.inner.get_context()
+    }
+    pub fn get_stats(&self) -> MachineStats {
+        self.inner.get_stats()
+    }
+    pub fn get_user_context(&self) -> &S {
+        self.inner.get_user_context()
+    }
+    pub fn get_user_context_mut(&mut self) -> &mut S {
+        self.inner.get_user_context_mut()
+    }
+    /// Run the handler for the current state once and move to the state it returns
+    pub fn step(&mut self) -> TransitionOutcome {
+        block_on(self.inner.step())
+    }
+    /// Step through the ticks recorded in `journal` for the machine of the same name,
+    /// handing its external events to `feed` to apply to the user context. Stops at the
+    /// first tick that does not do what it did when recorded. See the
+    /// [`journal`](crate::journal) module.
+    ///
+    /// Returns [`Error::Journal`] if `journal` cannot be read or parsed.
+    #[cfg(feature = "journal")]
+    pub fn replay(
+        &mut self,
+        journal: impl BufRead,
+        feed: impl FnMut(&ExternalEvent, &mut S),
+    ) -> Result<ReplayReport, Error> {
+        block_on(self.inner.replay(journal, feed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::builder::StateMachineBuilder;
+    use crate::transition::Transition;
+
+    fn test1(_: StateMachineContext) -> String {
+        "test2".to_string()
+    }
+    fn test2(_: StateMachineContext) -> String {
+        "missing".to_string()
+    }
+    #[test]
+    fn test_step() {
+        let mut machine = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .build_step_machine();
+        assert_eq!(
+            machine.step(),
+            TransitionOutcome::Transitioned(Transition {
+                from: "test1".into(),
+                to: "test2".into()
+            })
+        );
+        machine.step();
+        assert_eq!(machine.get_context().current_state, "missing");
+        assert_eq!(
+            machine.step(),
+            TransitionOutcome::UnknownState("missing".into())
+        );
+    }
+}
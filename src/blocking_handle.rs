@@ -0,0 +1,251 @@
+//! Synchronous handle for controlling an async StateMachine
+//!
+//! A [`BlockingHandle`] exposes the control and query methods of a
+//! [`StateMachineHandle`] as plain functions, so synchronous code such as a CLI or a
+//! plugin host can drive an async machine from its own threads. Each call blocks the
+//! calling thread until the async operation completes.
+//!
+#![cfg_attr(
+    any(feature = "tokio", feature = "smol", feature = "async-std"),
+    doc = "```rust"
+)]
+#![cfg_attr(
+    not(any(feature = "tokio", feature = "smol", feature = "async-std")),
+    doc = "```rust,ignore"
+)]
+//! use autostatemachine::{StateMachineBuilder, StateMachineContext};
+//! # #[tokio::main]
+//! # async fn main() {
+//! async fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let client = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build();
+//! let handle = client.blocking_handle();
+//! std::thread::spawn(move || {
+//!     handle.run().unwrap();
+//!     handle.pause();
+//!     handle.stop();
+//! })
+//! .join()
+//! .unwrap();
+//! # }
+//! ```
+use futures::{channel::mpsc::Receiver, executor::BlockingStream};
+use std::{future::Future, time::Duration};
+
+use crate::context::{StateMachineContext, StopMode, UserContext};
+use crate::error::Error;
+use crate::event::MachineEvent;
+use crate::handle::{Sleep, StateMachineHandle};
+use crate::history::History;
+#[cfg(feature = "serde")]
+use crate::snapshot::MachineSnapshot;
+use crate::stats::MachineStats;
+use crate::transition::Transition;
+
+/// Cloneable handle exposing the methods of a [`StateMachineHandle`] synchronously.
+///
+/// Safe to call from threads outside the runtime. When created inside a tokio runtime
+/// the handle remembers it, so handlers relying on tokio's timers or `tokio::spawn`
+/// keep working when stepped from another thread. Otherwise [`run`](Self::run) returns
+/// [`Error::NoRuntime`] if the machine's runtime cannot be used from the calling thread.
+/// The methods block, so they must not be called from async code or from inside a
+/// handler or hook of the same machine.
+pub struct BlockingHandle<S: 'static> {
+    handle: StateMachineHandle<S>,
+    #[cfg(feature = "tokio")]
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+impl<S: 'static> Clone for BlockingHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            #[cfg(feature = "tokio")]
+            runtime: self.runtime.clone(),
+        }
+    }
+}
+
+impl<S> BlockingHandle<S>
+where
+    S: Send + Sync + 'static,
+{
+    pub(crate) fn new(handle: StateMachineHandle<S>) -> Self {
+        Self {
+            handle,
+            #[cfg(feature = "tokio")]
+            runtime: tokio::runtime::Handle::try_current().ok(),
+        }
+    }
+    /// Drive `future` to completion on the calling thread, inside the tokio runtime the
+    /// handle was created in, if any
+    fn block_on<T>(&self, future: impl Future<Output = T>) -> T {
+        #[cfg(feature = "tokio")]
+        let _runtime = self.runtime.as_ref().map(|runtime| runtime.enter());
+        futures::executor::block_on(future)
+    }
+    /// The async handle this was created from
+    pub fn handle(&self) -> StateMachineHandle<S> {
+        self.handle.clone()
+    }
+    pub fn get_context(&self) -> StateMachineContext {
+        self.block_on(self.handle.get_context())
+    }
+    pub fn get_history(&self) -> History {
+        self.block_on(self.handle.get_history())
+    }
+    pub fn get_stats(&self) -> MachineStats {
+        self.block_on(self.handle.get_stats())
+    }
+    pub fn record_external(&self, name: &str, data: &str) {
+        self.block_on(self.handle.record_external(name, data))
+    }
+    #[cfg(feature = "serde")]
+    pub fn snapshot(&self) -> MachineSnapshot<S> {
+        self.block_on(self.handle.snapshot())
+    }
+    #[cfg(feature = "serde")]
+    pub fn snapshot_with_context(&self) -> MachineSnapshot<S>
+    where
+        S: Clone,
+    {
+        self.block_on(self.handle.snapshot_with_context())
+    }
+    /// Lock the user context, blocking while a tick is in progress
+    pub fn get_user_context(&self) -> UserContext<'_, S> {
+        self.block_on(self.handle.get_user_context())
+    }
+    pub fn get_tick_rate(&self) -> &Duration {
+        self.handle.get_tick_rate()
+    }
+    pub fn is_running(&self) -> bool {
+        self.handle.is_running()
+    }
+    /// Iterate over every [`MachineEvent`] from now on, blocking while none is
+    /// pending. See [`StateMachineHandle::subscribe`].
+    pub fn subscribe(&self) -> BlockingStream<Receiver<MachineEvent>> {
+        futures::executor::block_on_stream(self.handle.subscribe())
+    }
+    /// Start the run loop. See [`StateMachineHandle::run`].
+    pub fn run(&self) -> Result<(), Error> {
+        self.block_on(self.handle.run())
+    }
+    pub fn pause(&self) {
+        self.block_on(self.handle.pause())
+    }
+    pub fn resume(&self) {
+        self.block_on(self.handle.resume())
+    }
+    pub fn step(&self) -> Result<Transition, Error> {
+        self.block_on(self.handle.step())
+    }
+    pub fn run_ticks(&self, n: usize) -> Result<Vec<Transition>, Error> {
+        self.block_on(self.handle.run_ticks(n))
+    }
+    pub fn run_until<P>(&self, predicate: P) -> Result<Vec<Transition>, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        self.block_on(self.handle.run_until(predicate))
+    }
+    /// Block until the machine is in `state`. See [`StateMachineHandle::wait_for_state`].
+    pub fn wait_for_state(
+        &self,
+        state: &str,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error> {
+        self.wait_until(|context| context.is_in(state), timeout)
+    }
+    /// Block until `predicate` holds for the live context. See
+    /// [`StateMachineHandle::wait_until`]. The timeout is measured by blocking the
+    /// calling thread, so unlike the async handle this needs no runtime.
+    pub fn wait_until<P>(
+        &self,
+        predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        self.block_on(
+            self.handle
+                .shared
+                .wait_until(&Sleep::Thread, predicate, timeout),
+        )
+    }
+    /// Stop the machine and block until its run loop has exited. See
+    /// [`StateMachineHandle::stop`].
+    pub fn stop(&self) {
+        self.block_on(self.handle.stop())
+    }
+    pub fn stop_with(&self, mode: StopMode) {
+        self.block_on(self.handle.stop_with(mode))
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use crate::builder::StateMachineBuilder;
+
+    async fn test1(_: StateMachineContext) -> String {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        "test2".to_string()
+    }
+    async fn test2(_: StateMachineContext) -> String {
+        "test1".to_string()
+    }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_blocking_handle_from_thread() {
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        let handle = client.blocking_handle();
+        std::thread::spawn(move || {
+            handle.run().unwrap();
+            assert_eq!(handle.run(), Err(Error::AlreadyRunning));
+            handle
+                .wait_for_state("test2", Some(Duration::from_secs(1)))
+                .unwrap();
+            handle.pause();
+            assert_eq!(handle.get_context().current_state, "test2");
+            // Steps a handler that sleeps on tokio's timer, from outside the runtime
+            assert_eq!(handle.run_ticks(2).unwrap().len(), 2);
+            assert_eq!(handle.get_context().current_state, "test2");
+            handle.stop();
+            assert!(!handle.is_running());
+            assert_eq!(handle.get_context().current_state, "test1");
+        })
+        .join()
+        .unwrap();
+    }
+    #[test]
+    fn test_blocking_handle_off_runtime() {
+        // Built and driven without ever entering tokio
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .build();
+        let handle = client.blocking_handle();
+        std::thread::spawn(move || {
+            assert_eq!(handle.run(), Err(Error::NoRuntime));
+            assert!(!handle.is_running());
+            let waited = handle.wait_for_state("test2", Some(Duration::from_millis(10)));
+            assert_eq!(waited.err(), Some(Error::Timeout));
+            let async_handle = handle.handle();
+            let timed = async_handle.wait_until(|_| false, Some(Duration::ZERO));
+            let timed = futures::executor::block_on(timed);
+            assert_eq!(timed.err(), Some(Error::NoRuntime));
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -21,29 +21,152 @@
 //!     .tick_rate(Duration::from_millis(100))
 //!     .build();
 //! ```
-use std::{collections::HashMap, time::Duration};
+use std::sync::Arc;
 
-use crate::callback::IntoCallback;
+use crate::callback::{
+    BlockingExecutor, IntoBlockingCallback, IntoCallback, IntoLocalCallback, LocalCallback,
+};
+use crate::flavor::{Flavor, Local, Threaded};
+use crate::handle::{MachineConfig, MachineParts};
+use crate::runtime::{Executor, LocalExecutor, Runtime, Timer, Worker};
+use crate::step::StepMachine;
 use crate::StateMachine;
 
-use crate::callback::{Callback, StoredCallback};
+use crate::callback::Callback;
+/// Expands to the setters every builder shares, whatever the flavor of the machine it
+/// builds, on a builder whose `parts` field holds its [`Parts`](crate::machine::Parts)
+macro_rules! shared_setters {
+    () => {
+        /// Set what `stop()` does with the current state. Defaults to
+        /// [`StopMode::Reset`](crate::context::StopMode::Reset).
+        pub fn stop_mode(mut self, stop_mode: $crate::context::StopMode) -> Self {
+            self.parts.stop_mode = stop_mode;
+            self
+        }
+        pub fn tick_rate(mut self, tick_rate: std::time::Duration) -> Self {
+            self.parts.tick_rate = tick_rate;
+            self
+        }
+        /// Remember the last `capacity` transitions in the [`History`](crate::history::History)
+        /// of the machine, or none with 0. Defaults to
+        /// [`DEFAULT_CAPACITY`](crate::history::DEFAULT_CAPACITY).
+        pub fn history(mut self, capacity: usize) -> Self {
+            self.parts.history = capacity;
+            self
+        }
+        /// Make `name` a compound state containing `children`, so handlers returning `name`
+        /// enter `initial_child`. The handler of `name`, if it has one, runs before those of
+        /// its children and can transition for them. See the [`hierarchy`](crate::hierarchy)
+        /// module.
+        pub fn compound_state(
+            mut self,
+            name: &str,
+            initial_child: &str,
+            children: &[&str],
+        ) -> Self {
+            self.parts.hierarchy.compound.insert(
+                name.to_string(),
+                $crate::state::Compound {
+                    initial: initial_child.to_string(),
+                    children: children.iter().map(|child| child.to_string()).collect(),
+                },
+            );
+            self
+        }
+        /// Make `name` a parallel state, whose `regions` are all active at once while the
+        /// machine is in it. Each region is a compound state entered at its initial child,
+        /// and every tick runs the handlers of every region in turn. See the
+        /// [`hierarchy`](crate::hierarchy) module.
+        pub fn parallel_state(mut self, name: &str, regions: &[&str]) -> Self {
+            let parallel = self.parts.hierarchy.parallel.entry(name.to_string());
+            let regions = regions.iter().map(|region| region.to_string());
+            parallel.or_default().regions = regions.collect();
+            self
+        }
+        /// Mark `name` as a final state of its region
+        pub fn final_state(mut self, name: &str) -> Self {
+            self.parts.hierarchy.finals.insert(name.to_string());
+            self
+        }
+        /// Leave parallel state `name` for `to` once every one of its regions is in a final
+        /// state
+        pub fn join(mut self, name: &str, to: &str) -> Self {
+            let parallel = self.parts.hierarchy.parallel.entry(name.to_string());
+            parallel.or_default().join = Some(to.to_string());
+            self
+        }
+        /// Name the machine in the `machine` label of its metrics, the `machine` field of
+        /// its span and its journal entries, when the `metrics`, `tracing` or `journal`
+        /// feature is enabled. Defaults to `state_machine`.
+        pub fn name(mut self, name: &str) -> Self {
+            self.parts.name = name.to_string();
+            self
+        }
+        /// Start the machine where `snapshot` was taken instead of at the initial state,
+        /// with the user context of the snapshot if it has one. See the
+        /// [`snapshot`](crate::snapshot) module.
+        #[cfg(feature = "serde")]
+        pub fn restore(mut self, snapshot: $crate::snapshot::MachineSnapshot<S>) -> Self {
+            self.parts.restore(snapshot);
+            self
+        }
+        /// Write a checkpoint to `store` under `id` after every transition and when the
+        /// machine stops, and start from the last checkpoint there if there is one. See
+        /// the [`checkpoint`](crate::checkpoint) module.
+        ///
+        /// Returns [`Error::Checkpoint`](crate::Error::Checkpoint) if the last checkpoint
+        /// cannot be loaded.
+        #[cfg(feature = "checkpoint")]
+        pub fn resume_from(
+            mut self,
+            store: impl $crate::checkpoint::CheckpointStore + 'static,
+            id: &str,
+        ) -> Result<Self, $crate::Error>
+        where
+            S: serde::Serialize + serde::de::DeserializeOwned,
+        {
+            self.parts.resume_from(std::sync::Arc::new(store), id)?;
+            Ok(self)
+        }
+        /// Only checkpoint after every `ticks`th tick, and when the machine stops
+        #[cfg(feature = "checkpoint")]
+        pub fn checkpoint_every(mut self, ticks: u64) -> Self {
+            self.parts.checkpoint_every = ticks;
+            self
+        }
+        /// Append every transition, lifecycle change, external event and failure of the
+        /// machine to `journal`. See the [`journal`](crate::journal) module.
+        #[cfg(feature = "journal")]
+        pub fn journal(mut self, journal: $crate::journal::Journal) -> Self {
+            self.parts.journal = Some(journal);
+            self
+        }
+        pub fn initial_state(mut self, initial_state: String) -> Self {
+            self.parts.initial_state = Some(initial_state);
+            self
+        }
+    };
+}
+pub(crate) use shared_setters;
+
 /// Builder for StateMachine
-pub struct StateMachineBuilder<S> {
-    handlers: HashMap<String, StoredCallback<S>>,
-    tick_rate: Duration,
-    initial_state: Option<String>,
-    user_context: S,
+pub struct StateMachineBuilder<S, F: Flavor<S> = Threaded> {
+    parts: MachineParts<S, F>,
+    executor: Option<Arc<F::Executor>>,
+    timer: Option<Arc<dyn Timer>>,
+    /// Shared with every blocking handler, bound on build to the executor or a worker
+    blocking_executor: BlockingExecutor,
 }
 
 impl<S> StateMachineBuilder<S>
 where
-    S: Clone + Send + Sync,
+    S: Send + Sync + 'static,
 {
     /// Create a new StateMachineBuilder
     ///
     /// # Arguments
     /// * `user_context` - The user context to be passed to the callbacks.
-    /// Intended to act as some sort of state you can use in your callbacks.
+    ///   Intended to act as some sort of state you can use in your callbacks.
     ///
     /// # Example
     /// ```rust no_run
@@ -51,21 +174,24 @@ where
     /// use std::sync::{Arc, Mutex};
     /// // Pass it in () if you don't care about user_context
     /// let client = StateMachineBuilder::new(()).build();
-    /// // If you want to modify the user_context in your handlers,
-    /// // use Arc<Mutex<T>> or Arc<RwLock<T>> to wrap your user_context
-    /// // Then, the updates you make in your handlers will also be reflected
-    /// // outside of the handlers
+    /// // Handlers taking `&mut S` modify the machine's own user_context.
+    /// // If extractor handlers should modify it too, use Arc<Mutex<T>> or
+    /// // Arc<RwLock<T>> to wrap your user_context, as `State` hands out clones
     /// let client = StateMachineBuilder::new(Arc::new(Mutex::new(0))).build();
     /// ```
     pub fn new(user_context: S) -> Self {
         Self {
-            handlers: HashMap::new(),
-            tick_rate: Duration::from_millis(50),
-            initial_state: None,
-            user_context,
+            parts: MachineParts::<S>::new(user_context),
+            executor: None,
+            timer: None,
+            blocking_executor: BlockingExecutor::default(),
         }
     }
     /// Add a state to the StateMachine
+    ///
+    /// `f` either takes extractors, or borrows the context and the user context as
+    /// `async fn(&StateMachineContext, &mut S) -> String`. Borrowing handlers may hold
+    /// both borrows across awaits and nothing is cloned for them.
     /// # Arguments
     /// * `name` - The name of the state
     /// * `f` - The callback to be called when the state is active
@@ -74,10 +200,15 @@ where
     /// use autostatemachine::{StateMachineBuilder, StateMachineContext};
     /// async fn test1(_: StateMachineContext) -> String {
     ///   println!("test1");
+    ///   "test2".to_string()
+    /// }
+    /// async fn test2(_: &StateMachineContext, count: &mut u32) -> String {
+    ///   *count += 1;
     ///   "test1".to_string()
     /// }
-    /// let client = StateMachineBuilder::new(())
+    /// let client = StateMachineBuilder::new(0)
     ///  .add_state("test".to_string(), test1)
+    ///  .add_state("test2".to_string(), test2)
     ///  .initial_state("test".to_string())
     ///  .build();
     ///  ```
@@ -86,28 +217,214 @@ where
         name: String,
         f: impl IntoCallback<I, S, Callback = C>,
     ) -> Self {
-        self.handlers.insert(name, Box::new(f.into_callback()));
+        self.parts
+            .handlers
+            .insert(name, Box::new(f.into_callback()));
         self
     }
-    pub fn tick_rate(mut self, tick_rate: Duration) -> Self {
-        self.tick_rate = tick_rate;
+    /// Add a state whose handler is synchronous, such as a CPU-heavy step or a call into a
+    /// blocking library. Extractors are evaluated as for [`add_state`](Self::add_state),
+    /// then the handler runs through [`Executor::spawn_blocking`] so it never stalls the
+    /// runtime, and the state it returns is applied like any other transition. Without
+    /// an executor, such as a step machine built with no runtime feature, blocking
+    /// handlers share one worker thread.
+    /// # Example
+    /// ```rust
+    /// use autostatemachine::{StateMachineBuilder, StateMachineContext};
+    /// fn crunch(_: StateMachineContext) -> String {
+    ///   std::thread::sleep(std::time::Duration::from_millis(10));
+    ///   "crunch".to_string()
+    /// }
+    /// let client = StateMachineBuilder::new(())
+    ///  .add_blocking_state("crunch".to_string(), crunch)
+    ///  .initial_state("crunch".to_string())
+    ///  .build();
+    ///  ```
+    pub fn add_blocking_state<I, C: Callback<S> + 'static>(
+        mut self,
+        name: String,
+        f: impl IntoBlockingCallback<I, S, Callback = C>,
+    ) -> Self {
+        let callback = f.into_blocking_callback(self.blocking_executor.clone());
+        self.parts.handlers.insert(name, Box::new(callback));
+        self
+    }
+    /// Add a hook that runs once when the machine is stopped, after the last handler has
+    /// returned and before `stop()` resolves. Hooks take the same extractors as state
+    /// callbacks and run in the order they were added.
+    /// # Example
+    /// ```rust
+    /// use autostatemachine::{StateMachineBuilder, StateMachineContext};
+    /// async fn idle(_: StateMachineContext) -> String {
+    ///   "idle".to_string()
+    /// }
+    /// async fn cleanup(context: StateMachineContext) {
+    ///   println!("stopped in {}", context.current_state);
+    /// }
+    /// let client = StateMachineBuilder::new(())
+    ///  .add_state("idle".to_string(), idle)
+    ///  .on_stop(cleanup)
+    ///  .initial_state("idle".to_string())
+    ///  .build();
+    ///  ```
+    pub fn on_stop<I, C: Callback<S, ()> + 'static>(
+        mut self,
+        f: impl IntoCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        self.parts.on_stop.push(Box::new(f.into_callback()));
         self
     }
-    pub fn initial_state(mut self, initial_state: String) -> Self {
-        self.initial_state = Some(initial_state);
+    /// Add a hook that runs whenever a transition enters `state`, after the handler that
+    /// made it. Hooks take the same extractors as state callbacks and see the context
+    /// from before the transition. Entering a compound state runs the hooks of its
+    /// parents first, and a state returning its own name is not entered again. The
+    /// states a machine starts in are entered before the handlers of its first tick.
+    pub fn on_enter<I, C: Callback<S, ()> + 'static>(
+        mut self,
+        state: &str,
+        f: impl IntoCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        let hooks = self.parts.hooks.enter.entry(state.to_string()).or_default();
+        hooks.push(Box::new(f.into_callback()));
         self
     }
-    pub fn build(self) -> StateMachine<S> {
-        if self.handlers.is_empty() {
-            panic!("No states added");
+    /// Add a hook that runs whenever a transition leaves `state`, before the hooks of
+    /// the states it enters. Leaving a compound state runs the hooks of its children
+    /// first. A stopping run loop leaves the states it is in before the `on_stop` hooks.
+    pub fn on_exit<I, C: Callback<S, ()> + 'static>(
+        mut self,
+        state: &str,
+        f: impl IntoCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        let hooks = self.parts.hooks.exit.entry(state.to_string()).or_default();
+        hooks.push(Box::new(f.into_callback()));
+        self
+    }
+    /// Run the machine on `runtime`, which provides both the [`Executor`] and the
+    /// [`Timer`]. See the [`runtime`](crate::runtime) module for what is available.
+    pub fn runtime<R: Executor + Timer + Clone>(self, runtime: R) -> Self {
+        self.executor(runtime.clone()).timer(runtime)
+    }
+    /// Spawn the run loop with `executor` instead of the default for the enabled features
+    pub fn executor(mut self, executor: impl Executor) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+}
+
+impl<S> StateMachineBuilder<S, Local>
+where
+    S: 'static,
+{
+    /// Create a builder for a [`LocalStateMachine`](crate::LocalStateMachine), whose
+    /// handlers, hooks and user context do not have to be `Send` or `Sync`. The run loop
+    /// is spawned on the current tokio `LocalSet` unless told otherwise with
+    /// [`runtime`](Self::runtime).
+    ///
+    /// # Example
+    /// ```rust no_run
+    /// use autostatemachine::StateMachineBuilder;
+    /// use std::{cell::RefCell, rc::Rc};
+    /// let client = StateMachineBuilder::new_local(Rc::new(RefCell::new(0))).build();
+    /// ```
+    pub fn new_local(user_context: S) -> Self {
+        Self {
+            parts: MachineParts::<S, Local>::new(user_context),
+            executor: None,
+            timer: None,
+            blocking_executor: BlockingExecutor::default(),
         }
-        let initial_state = self.initial_state.expect("Initial state not set");
-        StateMachine::new(
-            self.handlers,
-            self.tick_rate,
-            initial_state,
-            self.user_context,
-        )
+    }
+    /// Add a state to the local StateMachine. Takes the same extractors as
+    /// [`StateMachineBuilder::add_state`], but neither `f` nor its future has to be `Send`.
+    pub fn add_state<I, C: LocalCallback<S> + 'static>(
+        mut self,
+        name: String,
+        f: impl IntoLocalCallback<I, S, Callback = C>,
+    ) -> Self {
+        self.parts
+            .handlers
+            .insert(name, Box::new(f.into_callback()));
+        self
+    }
+    /// Add a hook that runs once when the machine is stopped. See
+    /// [`StateMachineBuilder::on_stop`].
+    pub fn on_stop<I, C: LocalCallback<S, ()> + 'static>(
+        mut self,
+        f: impl IntoLocalCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        self.parts.on_stop.push(Box::new(f.into_callback()));
+        self
+    }
+    /// Add a hook that runs whenever a transition enters `state`. See
+    /// [`StateMachineBuilder::on_enter`].
+    pub fn on_enter<I, C: LocalCallback<S, ()> + 'static>(
+        mut self,
+        state: &str,
+        f: impl IntoLocalCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        let hooks = self.parts.hooks.enter.entry(state.to_string()).or_default();
+        hooks.push(Box::new(f.into_callback()));
+        self
+    }
+    /// Add a hook that runs whenever a transition leaves `state`. See
+    /// [`StateMachineBuilder::on_exit`].
+    pub fn on_exit<I, C: LocalCallback<S, ()> + 'static>(
+        mut self,
+        state: &str,
+        f: impl IntoLocalCallback<I, S, (), Callback = C>,
+    ) -> Self {
+        let hooks = self.parts.hooks.exit.entry(state.to_string()).or_default();
+        hooks.push(Box::new(f.into_callback()));
+        self
+    }
+    /// Run the machine on `runtime`, which provides both the [`LocalExecutor`] and the
+    /// [`Timer`]
+    pub fn runtime<R: LocalExecutor + Timer + Clone>(self, runtime: R) -> Self {
+        self.executor(runtime.clone()).timer(runtime)
+    }
+    /// Spawn the run loop with `executor` instead of the current tokio `LocalSet`
+    pub fn executor(mut self, executor: impl LocalExecutor) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+}
+
+impl<S, F> StateMachineBuilder<S, F>
+where
+    S: 'static,
+    F: Flavor<S>,
+{
+    shared_setters!();
+    /// Sleep between ticks with `timer` instead of the default for the enabled features
+    pub fn timer(mut self, timer: impl Timer) -> Self {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+    pub fn build(self) -> StateMachine<S, F> {
+        let (config, runtime) = self.into_config();
+        StateMachine::from_config(config, runtime)
+    }
+    /// Build a [`StepMachine`] that ticks only when its `step()` is awaited, for
+    /// embedding in a loop you already own. No task is spawned and the tick rate is
+    /// only reported to handlers, never slept on.
+    pub fn build_step_machine(self) -> StepMachine<S, F> {
+        StepMachine::new(self.into_config().0)
+    }
+    fn into_config(self) -> (MachineConfig<S, F>, Option<Runtime<F::Executor>>) {
+        let config = self.parts.into_config();
+        let executor = self.executor.or_else(F::default_executor);
+        let timer = self
+            .timer
+            .or_else(|| Runtime::default_for_features().map(|default| default.timer));
+        let blocking = executor.as_ref().and_then(F::blocking_executor);
+        let _ = self
+            .blocking_executor
+            .set(blocking.unwrap_or_else(|| Arc::new(Worker::default())));
+        let runtime = executor
+            .zip(timer)
+            .map(|(executor, timer)| Runtime { executor, timer });
+        (config, runtime)
     }
 }
 #[cfg(test)]
@@ -121,6 +438,7 @@ mod tests {
         "test3".to_string()
     }
     use crate::{context::StateMachineContext, extractor::TickRate};
+    use std::time::Duration;
 
     use super::*;
     #[tokio::test]
@@ -139,8 +457,8 @@ mod tests {
             .build();
         assert_eq!(client.get_context().await.current_state, "test");
         assert_eq!(client.get_tick_rate(), &Duration::from_millis(50));
-        assert_eq!(client.get_user_context(), &());
-        assert_eq!(client.handlers.len(), 3);
+        assert_eq!(client.get_user_context().await, &());
+        assert_eq!(client.handle.shared.handlers.len(), 3);
     }
     #[tokio::test]
     #[should_panic]
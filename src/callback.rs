@@ -1,9 +1,14 @@
-use futures::future::BoxFuture;
+use std::sync::{Arc, OnceLock};
+
+use futures::future::{BoxFuture, LocalBoxFuture, Map};
+use futures::FutureExt;
 
 use crate::context::StateMachineContext;
 use crate::extractor::FromContext;
-pub trait IntoCallback<Input, S> {
-    type Callback: Callback<S>;
+use crate::runtime::{unblock, Executor};
+use crate::state::Target;
+pub trait IntoCallback<Input, S, O = Target> {
+    type Callback: Callback<S, O>;
 
     fn into_callback(self) -> Self::Callback;
 }
@@ -13,29 +18,30 @@ pub struct Wrapper<T, F> {
     pub marker: std::marker::PhantomData<T>,
 }
 
-pub trait Callback<S>: Send + Sync {
-    fn call(&self, context: &StateMachineContext, s: &mut S) -> BoxFuture<'static, String>;
+/// A state handler or lifecycle hook. `O` is what the callback resolves to: the next
+/// state for handlers, `()` for hooks. The returned future may borrow the context and
+/// the user context until it resolves.
+pub trait Callback<S, O = Target>: Send + Sync {
+    fn call<'a>(&'a self, context: &'a StateMachineContext, s: &'a mut S) -> BoxFuture<'a, O>;
 }
-pub type StoredCallback<S> = Box<dyn Callback<S>>;
+pub type StoredCallback<S, O = Target> = Box<dyn Callback<S, O>>;
 macro_rules! impl_callback {
     (
         $($(
                 $params:ident
         ),+)?
     ) => {
-        impl<Fut, F, $($($params,)+)? S> Callback<S> for Wrapper<( $($($params,)+)? ), F>
+        impl<Fut, F, $($($params,)+)? S, O> Callback<S, O> for Wrapper<( $($($params,)+)? ), F>
         where
             F: Fn($($($params),+)?)-> Fut + Send + Sync,
-            Fut: futures::Future<Output = String> + Send + 'static,
+            Fut: futures::Future + Send + 'static,
+            Fut::Output: Into<O>,
             $($($params: 'static + FromContext<S> + Send + Sync,)+)?
             S: 'static,
         {
-            fn call(&self, context: &StateMachineContext, s: &mut S) -> BoxFuture<'static, String> {
+            fn call<'a>(&'a self, context: &'a StateMachineContext, s: &'a mut S) -> BoxFuture<'a, O> {
                 let fut = (self.f)($($($params::from_context(context, s)),+)?);
-                Box::pin(async move {
-                    let result = fut.await;
-                    result
-                })
+                Box::pin(async move { fut.await.into() })
             }
         }
     }
@@ -51,10 +57,11 @@ macro_rules! impl_into_callback {
                 $params:ident
         ),+)?
     ) => {
-        impl<Fut, F, $($($params,)+)? S> IntoCallback<( $($($params,)+)? ), S> for F
+        impl<Fut, F, $($($params,)+)? S, O> IntoCallback<( $($($params,)+)? ), S, O> for F
         where
             F: Fn($($($params),+)?)-> Fut + Send + Sync,
-            Fut: futures::Future<Output = String> + Send + 'static,
+            Fut: futures::Future + Send + 'static,
+            Fut::Output: Into<O>,
             $($($params: 'static + FromContext<S> + Send + Sync,)+)?
             S: 'static,
         {
@@ -76,21 +83,23 @@ impl_into_callback!(T1, T2, T3);
 impl_into_callback!(T1, T2, T3, T4);
 
 // manual impl of callback and intocallback for no params
-impl<Fut, F, S> Callback<S> for Wrapper<(), F>
+impl<Fut, F, S, O> Callback<S, O> for Wrapper<(), F>
 where
     F: Fn() -> Fut + Send + Sync,
-    Fut: futures::Future<Output = String> + Send + 'static,
+    Fut: futures::Future + Send + 'static,
+    Fut::Output: Into<O>,
     S: 'static,
 {
-    fn call(&self, _: &StateMachineContext, _: &mut S) -> BoxFuture<'static, String> {
+    fn call<'a>(&'a self, _: &'a StateMachineContext, _: &'a mut S) -> BoxFuture<'a, O> {
         let fut = (self.f)();
-        Box::pin(fut)
+        Box::pin(async move { fut.await.into() })
     }
 }
-impl<Fut, F, S> IntoCallback<(), S> for F
+impl<Fut, F, S, O> IntoCallback<(), S, O> for F
 where
     F: Fn() -> Fut + Send + Sync,
-    Fut: futures::Future<Output = String> + Send + 'static,
+    Fut: futures::Future + Send + 'static,
+    Fut::Output: Into<O>,
     S: 'static,
 {
     type Callback = Wrapper<(), Self>;
@@ -102,3 +111,222 @@ where
         }
     }
 }
+
+pub trait IntoLocalCallback<Input, S, O = Target> {
+    type Callback: LocalCallback<S, O>;
+
+    fn into_callback(self) -> Self::Callback;
+}
+
+/// A [`Callback`] for local machines: neither the callback nor its future has to be
+/// `Send` or `Sync`.
+pub trait LocalCallback<S, O = Target> {
+    fn call<'a>(&'a self, context: &'a StateMachineContext, s: &'a mut S) -> LocalBoxFuture<'a, O>;
+}
+macro_rules! impl_local_callback {
+    (
+        $($(
+                $params:ident
+        ),+)?
+    ) => {
+        impl<Fut, F, $($($params,)+)? S, O> LocalCallback<S, O> for Wrapper<( $($($params,)+)? ), F>
+        where
+            F: Fn($($($params),+)?)-> Fut,
+            Fut: futures::Future + 'static,
+            Fut::Output: Into<O>,
+            $($($params: 'static + FromContext<S>,)+)?
+            S: 'static,
+        {
+            #[allow(unused_variables)]
+            fn call<'a>(&'a self, context: &'a StateMachineContext, s: &'a mut S) -> LocalBoxFuture<'a, O> {
+                let fut = (self.f)($($($params::from_context(context, s)),+)?);
+                Box::pin(async move { fut.await.into() })
+            }
+        }
+        impl<Fut, F, $($($params,)+)? S, O> IntoLocalCallback<( $($($params,)+)? ), S, O> for F
+        where
+            F: Fn($($($params),+)?)-> Fut,
+            Fut: futures::Future + 'static,
+            Fut::Output: Into<O>,
+            $($($params: 'static + FromContext<S>,)+)?
+            S: 'static,
+        {
+            type Callback = Wrapper<( $($($params,)+)? ), Self>;
+
+            fn into_callback(self) -> Self::Callback {
+                Wrapper {
+                    f: self,
+                    marker: Default::default(),
+                }
+            }
+        }
+    }
+}
+impl_local_callback!();
+impl_local_callback!(T1);
+impl_local_callback!(T1, T2);
+impl_local_callback!(T1, T2, T3);
+impl_local_callback!(T1, T2, T3, T4);
+
+/// Marks callbacks that borrow the contexts instead of using extractors
+pub struct Borrowed;
+
+/// An async fn taking `(&StateMachineContext, &mut S)`, whose future may hold both
+/// borrows across awaits
+pub trait BorrowingHandler<'a, S: 'a, O>: Send + Sync {
+    type Future: futures::Future<Output = O> + Send + 'a;
+
+    fn call(&self, context: &'a StateMachineContext, s: &'a mut S) -> Self::Future;
+}
+impl<'a, S: 'a, O: 'a, F, Fut> BorrowingHandler<'a, S, O> for F
+where
+    F: Fn(&'a StateMachineContext, &'a mut S) -> Fut + Send + Sync,
+    Fut: futures::Future + Send + 'a,
+    Fut::Output: Into<O>,
+{
+    type Future = Map<Fut, fn(Fut::Output) -> O>;
+
+    fn call(&self, context: &'a StateMachineContext, s: &'a mut S) -> Self::Future {
+        self(context, s).map(Into::into)
+    }
+}
+impl<F, S, O> Callback<S, O> for Wrapper<Borrowed, F>
+where
+    F: for<'a> BorrowingHandler<'a, S, O>,
+{
+    fn call<'a>(&'a self, context: &'a StateMachineContext, s: &'a mut S) -> BoxFuture<'a, O> {
+        Box::pin(self.f.call(context, s))
+    }
+}
+impl<F, S, O> IntoCallback<Borrowed, S, O> for F
+where
+    F: for<'a> BorrowingHandler<'a, S, O>,
+{
+    type Callback = Wrapper<Borrowed, Self>;
+
+    fn into_callback(self) -> Self::Callback {
+        Wrapper {
+            f: self,
+            marker: Default::default(),
+        }
+    }
+}
+
+/// A [`BorrowingHandler`] for local machines, whose future does not have to be `Send`
+pub trait LocalBorrowingHandler<'a, S: 'a, O> {
+    type Future: futures::Future<Output = O> + 'a;
+
+    fn call(&self, context: &'a StateMachineContext, s: &'a mut S) -> Self::Future;
+}
+impl<'a, S: 'a, O: 'a, F, Fut> LocalBorrowingHandler<'a, S, O> for F
+where
+    F: Fn(&'a StateMachineContext, &'a mut S) -> Fut,
+    Fut: futures::Future + 'a,
+    Fut::Output: Into<O>,
+{
+    type Future = Map<Fut, fn(Fut::Output) -> O>;
+
+    fn call(&self, context: &'a StateMachineContext, s: &'a mut S) -> Self::Future {
+        self(context, s).map(Into::into)
+    }
+}
+impl<F, S, O> LocalCallback<S, O> for Wrapper<Borrowed, F>
+where
+    F: for<'a> LocalBorrowingHandler<'a, S, O>,
+{
+    fn call<'a>(&'a self, context: &'a StateMachineContext, s: &'a mut S) -> LocalBoxFuture<'a, O> {
+        Box::pin(self.f.call(context, s))
+    }
+}
+impl<F, S, O> IntoLocalCallback<Borrowed, S, O> for F
+where
+    F: for<'a> LocalBorrowingHandler<'a, S, O>,
+{
+    type Callback = Wrapper<Borrowed, Self>;
+
+    fn into_callback(self) -> Self::Callback {
+        Wrapper {
+            f: self,
+            marker: Default::default(),
+        }
+    }
+}
+
+/// Where blocking handlers are offloaded to, bound by the builder once the machine's
+/// executor is known: its `spawn_blocking` for a `Send` executor, and otherwise one
+/// worker thread that runs the machine's blocking handlers in turn.
+pub(crate) type BlockingExecutor = Arc<OnceLock<Arc<dyn Executor>>>;
+
+/// Wrapper for synchronous handlers added with `add_blocking_state`
+pub struct BlockingWrapper<T, F> {
+    f: Arc<F>,
+    executor: BlockingExecutor,
+    marker: std::marker::PhantomData<T>,
+}
+
+/// A synchronous handler that is run off the async runtime
+pub trait IntoBlockingCallback<Input, S> {
+    type Callback: Callback<S>;
+
+    fn into_blocking_callback(self, executor: BlockingExecutor) -> Self::Callback;
+}
+
+/// Run `f` on the blocking executor, resolving to its result
+fn run_blocking<R: Send + 'static>(
+    executor: &BlockingExecutor,
+    f: impl FnOnce() -> R + Send + 'static,
+) -> impl futures::Future<Output = R> + Send + 'static {
+    let executor = executor
+        .get()
+        .expect("blocking handlers are bound to an executor on build");
+    unblock(&**executor, f)
+}
+
+macro_rules! impl_blocking_callback {
+    (
+        $($(
+                $params:ident
+        ),+)?
+    ) => {
+        impl<F, R, $($($params,)+)? S> Callback<S> for BlockingWrapper<( $($($params,)+)? ), F>
+        where
+            F: Fn($($($params),+)?) -> R + Send + Sync + 'static,
+            R: Into<Target>,
+            $($($params: 'static + FromContext<S> + Send + Sync,)+)?
+            S: 'static,
+        {
+            #[allow(unused_variables)]
+            fn call<'a>(&'a self, context: &'a StateMachineContext, s: &'a mut S) -> BoxFuture<'a, Target> {
+                // Extractors run here, only the handler itself is offloaded
+                $($(
+                    #[allow(non_snake_case)]
+                    let $params = $params::from_context(context, s);
+                )+)?
+                let f = self.f.clone();
+                Box::pin(run_blocking(&self.executor, move || f($($($params),+)?).into()))
+            }
+        }
+        impl<F, R, $($($params,)+)? S> IntoBlockingCallback<( $($($params,)+)? ), S> for F
+        where
+            F: Fn($($($params),+)?) -> R + Send + Sync + 'static,
+            R: Into<Target>,
+            $($($params: 'static + FromContext<S> + Send + Sync,)+)?
+            S: 'static,
+        {
+            type Callback = BlockingWrapper<( $($($params,)+)? ), Self>;
+
+            fn into_blocking_callback(self, executor: BlockingExecutor) -> Self::Callback {
+                BlockingWrapper {
+                    f: Arc::new(self),
+                    executor,
+                    marker: Default::default(),
+                }
+            }
+        }
+    }
+}
+impl_blocking_callback!();
+impl_blocking_callback!(T1);
+impl_blocking_callback!(T1, T2);
+impl_blocking_callback!(T1, T2, T3);
+impl_blocking_callback!(T1, T2, T3, T4);
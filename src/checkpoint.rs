@@ -0,0 +1,375 @@
+//! Automatic checkpoints of a StateMachine to a persistence store
+//!
+//! A machine built with `resume_from(store, id)` writes a checkpoint to the
+//! [`CheckpointStore`] after its transitions and once more when it stops. On startup
+//! the same call continues from the last checkpoint saved under `id`, with its user
+//! context, so a restarted service picks up where it was. `checkpoint_every(n)` only
+//! writes after every `n`th tick.
+//!
+//! Checkpoints are [`MachineSnapshot`]s, including the user context, encoded as JSON.
+//! Both shipped stores replace a checkpoint atomically, so a crash mid-write leaves the
+//! previous checkpoint intact:
+//!
+//! - [`FileStore`] keeps one file per machine in a directory.
+//! - [`SqliteStore`] keeps one row per machine in an SQLite database, with the `sqlite`
+//!   feature.
+//!
+//! Stores are called on a thread of their own, one write at a time, so a slow disk or
+//! database never blocks the executor. The next tick waits for the write, and
+//! `stop()` returns once the last one is done. A failed write does not stop the
+//! machine: it is published as
+//! [`MachineEvent::CheckpointFailed`](crate::MachineEvent::CheckpointFailed).
+//!
+//! ```rust,no_run
+//! use autostatemachine::{checkpoint::FileStore, StateMachineBuilder, StateMachineContext};
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! async fn count(_: StateMachineContext) -> String {
+//!     "count".to_string()
+//! }
+//! let client = StateMachineBuilder::new(0u64)
+//!     .add_state("count".to_string(), count)
+//!     .initial_state("count".to_string())
+//!     .checkpoint_every(10)
+//!     .resume_from(FileStore::new("/var/lib/my-service")?, "counter")?
+//!     .build();
+//! client.run().await?;
+//! # Ok(())
+//! # }
+//! ```
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::error::Error;
+use crate::machine::Control;
+use crate::runtime::{unblock, Worker};
+use crate::snapshot::MachineSnapshot;
+
+/// Where checkpoints are kept, by the id of their machine
+pub trait CheckpointStore: Send + Sync {
+    /// Replace the checkpoint of machine `id`. Either the new checkpoint or the old one
+    /// must survive a crash during the write.
+    fn save(&self, id: &str, checkpoint: &[u8]) -> io::Result<()>;
+    /// The last checkpoint saved for machine `id`, if any
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Keeps each checkpoint in `<dir>/<id>.checkpoint`
+///
+/// A checkpoint is written to a temporary file, synced and then renamed over the
+/// previous one, so readers never see half of it. Ids must be plain file names: ones
+/// that are empty, start with a dot or contain a path separator or NUL are refused with
+/// [`io::ErrorKind::InvalidInput`], so no id reaches outside `dir`.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Keep checkpoints in `dir`, creating it if needed
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        if id.is_empty() || id.starts_with('.') || id.contains(['/', '\\', '\0']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{id:?} is not a valid checkpoint id"),
+            ));
+        }
+        Ok(self.dir.join(format!("{id}.checkpoint")))
+    }
+}
+
+impl CheckpointStore for FileStore {
+    fn save(&self, id: &str, checkpoint: &[u8]) -> io::Result<()> {
+        let path = self.path(id)?;
+        let temporary = self.dir.join(format!(".{id}.checkpoint.tmp"));
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(checkpoint)?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        sync_dir(&self.dir)
+    }
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(id)?) {
+            Ok(checkpoint) => Ok(Some(checkpoint)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Make a rename in `dir` durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened to sync them here, and renames are durable already
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Keeps checkpoints in the `checkpoints` table of an SQLite database
+///
+/// Each checkpoint replaces the previous one in a single statement, which SQLite
+/// applies atomically.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Open the database at `path`, creating it and the table if needed
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Self::with_connection(rusqlite::Connection::open(path)?)
+    }
+    /// Use `connection`, creating the table if needed
+    pub fn with_connection(connection: rusqlite::Connection) -> rusqlite::Result<Self> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS checkpoints (
+                id TEXT PRIMARY KEY,
+                checkpoint BLOB NOT NULL
+            )",
+            (),
+        )?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+    fn connection(&self) -> std::sync::MutexGuard<'_, rusqlite::Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl CheckpointStore for SqliteStore {
+    fn save(&self, id: &str, checkpoint: &[u8]) -> io::Result<()> {
+        self.connection()
+            .execute(
+                "INSERT INTO checkpoints (id, checkpoint) VALUES (?1, ?2)
+                ON CONFLICT (id) DO UPDATE SET checkpoint = excluded.checkpoint",
+                (id, checkpoint),
+            )
+            .map(drop)
+            .map_err(io::Error::other)
+    }
+    fn load(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension;
+        self.connection()
+            .query_row(
+                "SELECT checkpoint FROM checkpoints WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(io::Error::other)
+    }
+}
+
+/// Writes the checkpoints of one machine
+pub(crate) struct Checkpointer<S> {
+    store: Arc<dyn CheckpointStore>,
+    id: String,
+    /// Write after every this many ticks
+    pub(crate) every: u64,
+    /// Captured where `S: Serialize` is known, as the machine does not require it
+    encode: fn(MachineSnapshot, &S) -> serde_json::Result<Vec<u8>>,
+    /// Calls the store, off the executor
+    worker: Worker,
+}
+
+fn encode<S: Serialize>(
+    snapshot: MachineSnapshot,
+    user_context: &S,
+) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&snapshot.with_user_context(Some(user_context)))
+}
+
+fn checkpoint_error(id: &str, error: impl std::fmt::Display) -> Error {
+    Error::Checkpoint(format!("checkpoint {id:?}: {error}"))
+}
+
+impl<S> Checkpointer<S> {
+    /// Load the last checkpoint of `id` from `store`, and checkpoint there from now on
+    pub(crate) fn resume(
+        store: Arc<dyn CheckpointStore>,
+        id: &str,
+    ) -> Result<(Self, Option<MachineSnapshot<S>>), Error>
+    where
+        S: Serialize + DeserializeOwned,
+    {
+        let snapshot = match store.load(id).map_err(|e| checkpoint_error(id, e))? {
+            Some(checkpoint) => {
+                Some(serde_json::from_slice(&checkpoint).map_err(|e| checkpoint_error(id, e))?)
+            }
+            None => None,
+        };
+        let checkpointer = Self {
+            store,
+            id: id.to_string(),
+            every: 1,
+            encode: encode::<S>,
+            worker: Worker::default(),
+        };
+        Ok((checkpointer, snapshot))
+    }
+    /// Write a checkpoint of the machine behind `control`, if one is due or `always`.
+    /// It is encoded straight away, while the caller holds the user context, and the
+    /// returned future resolves once the store has saved it. Callers keep holding the
+    /// user context until then, so checkpoints are never written out of order.
+    pub(crate) fn checkpoint<'a>(
+        &'a self,
+        control: &'a Mutex<Control>,
+        user_context: &S,
+        always: bool,
+    ) -> impl std::future::Future<Output = ()> + 'a {
+        let lock = move || control.lock().unwrap_or_else(PoisonError::into_inner);
+        let encoded = {
+            let control = lock();
+            let due = always || control.stats.ticks() % self.every.max(1) == 0;
+            due.then(|| control.snapshot())
+        }
+        .map(|snapshot| (self.encode)(snapshot, user_context));
+        async move {
+            let saved = match encoded {
+                None => return,
+                Some(Ok(checkpoint)) => {
+                    let (store, id) = (self.store.clone(), self.id.clone());
+                    unblock(&self.worker, move || store.save(&id, &checkpoint)).await
+                }
+                Some(Err(error)) => Err(io::Error::other(error)),
+            };
+            if let Err(error) = saved {
+                lock().checkpoint_failed(checkpoint_error(&self.id, error));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::{StateMachine, StateMachineBuilder, StateMachineContext, StopMode};
+    use std::time::Duration;
+
+    #[test]
+    fn test_file_store() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{}", std::process::id()));
+        let store = FileStore::new(&dir).unwrap();
+        assert_eq!(store.load("machine").unwrap(), None);
+        store.save("machine", b"first").unwrap();
+        store.save("machine", b"second").unwrap();
+        assert_eq!(
+            store.load("machine").unwrap().as_deref(),
+            Some(&b"second"[..])
+        );
+        // Only the checkpoint is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        for id in ["", "../machine", "a/b", "a\\b", ".hidden"] {
+            let refused = store.save(id, b"escaped").unwrap_err();
+            assert_eq!(refused.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(
+                store.load(id).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+    fn count(context: &StateMachineContext, count: &mut u32) -> String {
+        *count += 1;
+        match context.current_state.as_str() {
+            "test1" => "test2".to_string(),
+            _ => "test1".to_string(),
+        }
+    }
+    fn counter(store: impl CheckpointStore + 'static) -> StateMachine<u32> {
+        StateMachineBuilder::new(0)
+            .add_state("test1".to_string(), count)
+            .add_state("test2".to_string(), count)
+            .initial_state("test1".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .stop_mode(StopMode::Preserve)
+            .resume_from(store, "counter")
+            .unwrap()
+            .build()
+    }
+    #[test]
+    fn test_resume_from() {
+        let dir = std::env::temp_dir().join(format!("resume-{}", std::process::id()));
+        let client = counter(FileStore::new(&dir).unwrap());
+        client.run().unwrap();
+        client.wait_for_state("test2", None).unwrap();
+        client.pause();
+        client.run_ticks(2).unwrap();
+        client.stop();
+        drop(client);
+        // Restarted where the last one stopped
+        let client = counter(FileStore::new(&dir).unwrap());
+        assert_eq!(client.get_context().current_state, "test2");
+        assert_eq!(*client.get_user_context(), 3);
+        assert_eq!(client.get_history().last().map(|t| t.tick), Some(3));
+        fs::write(dir.join("counter.checkpoint"), b"{").unwrap();
+        assert!(matches!(
+            StateMachineBuilder::new(0)
+                .add_state("test1".to_string(), count)
+                .initial_state("test1".to_string())
+                .resume_from(FileStore::new(&dir).unwrap(), "counter"),
+            Err(Error::Checkpoint(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+    /// Remembers which threads it was called on
+    #[derive(Clone, Default)]
+    struct Threads(Arc<Mutex<Vec<std::thread::ThreadId>>>);
+
+    impl CheckpointStore for Threads {
+        fn save(&self, _: &str, _: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().push(std::thread::current().id());
+            Ok(())
+        }
+        fn load(&self, _: &str) -> io::Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+    }
+    #[test]
+    fn test_saved_off_caller() {
+        let store = Threads::default();
+        let client = counter(store.clone());
+        client.run().unwrap();
+        client.wait_for_state("test2", None).unwrap();
+        client.pause();
+        client.step().unwrap();
+        client.stop();
+        // Once after each tick and once on stop, all on the same thread of their own
+        let threads = store.0.lock().unwrap();
+        assert_eq!(threads.len(), 3);
+        assert!(threads.iter().all(|thread| *thread == threads[0]));
+        assert_ne!(threads[0], std::thread::current().id());
+    }
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store() {
+        let store =
+            SqliteStore::with_connection(rusqlite::Connection::open_in_memory().unwrap()).unwrap();
+        assert_eq!(store.load("machine").unwrap(), None);
+        store.save("machine", b"first").unwrap();
+        store.save("machine", b"second").unwrap();
+        store.save("other", b"other").unwrap();
+        assert_eq!(
+            store.load("machine").unwrap().as_deref(),
+            Some(&b"second"[..])
+        );
+    }
+}
@@ -1,25 +1,93 @@
-use std::time::Duration;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+use futures::lock::MutexGuard;
+
+use crate::history::History;
+use crate::state::StateName;
 
 use crate::extractor::FromContext;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum LifeCycle {
     Running,
     Paused,
     Stopped,
 }
 
+/// What happens to `current_state` when a machine is stopped
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StopMode {
+    /// Return to `initial_state`, so the next `run()` starts from the beginning
+    #[default]
+    Reset,
+    /// Keep `current_state`, so the next `run()` resumes where the machine left off
+    Preserve,
+}
+
 // S is for user context (state)
 // E is for States
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StateMachineContext {
     pub tick_rate: Duration,
-    pub current_state: String,
-    pub initial_state: String,
+    pub current_state: StateName,
+    pub initial_state: StateName,
+    /// The state each region is in while `current_state` is a parallel state, in the
+    /// order the regions were declared, and empty otherwise
+    pub regions: Vec<StateName>,
     pub life_cycle: LifeCycle,
+    /// The most recent transitions, oldest first
+    pub history: History,
+}
+
+impl StateMachineContext {
+    /// The state `region` of the current parallel state is in
+    pub fn region(&self, region: &str) -> Option<&StateName> {
+        self.regions.iter().find(|state| state.is_in(region))
+    }
+    /// Whether `state` is active: the current state, a state containing it, or the
+    /// state of a region or one containing that
+    pub fn is_in(&self, state: &str) -> bool {
+        self.current_state.is_in(state) || self.regions.iter().any(|region| region.is_in(state))
+    }
 }
 impl<S> FromContext<S> for StateMachineContext {
     fn from_context(context: &StateMachineContext, _user_state: &S) -> Self {
         context.clone()
     }
 }
+
+/// The user context of a machine, locked until this is dropped. Derefs to the user
+/// context and compares equal to a reference to one, like the `&S` it replaces.
+pub struct UserContext<'a, S>(pub(crate) MutexGuard<'a, S>);
+
+impl<S> Deref for UserContext<'_, S> {
+    type Target = S;
+    fn deref(&self) -> &S {
+        &self.0
+    }
+}
+
+impl<S> DerefMut for UserContext<'_, S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.0
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for UserContext<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<S: PartialEq> PartialEq<&S> for UserContext<'_, S> {
+    fn eq(&self, other: &&S) -> bool {
+        **self == **other
+    }
+}
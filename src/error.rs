@@ -0,0 +1,47 @@
+//! Errors returned by StateMachine control methods
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Error {
+    /// `run()` was called while a run loop is already active for the machine
+    AlreadyRunning,
+    /// Stepping was requested while the machine is not running and paused
+    NotPaused,
+    /// The machine is in a state that has no handler
+    UnknownState(String),
+    /// `run()` was called on a machine built without an executor and timer, or from
+    /// outside the runtime they need
+    NoRuntime,
+    /// The handler for the state panicked, stopping the run loop
+    HandlerPanicked(String),
+    /// A handler in a region of a parallel state returned a state in another region,
+    /// or the parallel state itself
+    OutsideRegion { from: String, to: String },
+    /// A wait for the machine to reach a state or condition timed out
+    Timeout,
+    /// A checkpoint could not be saved or loaded
+    Checkpoint(String),
+    /// A journal could not be read for replay
+    Journal(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AlreadyRunning => write!(f, "state machine is already running"),
+            Error::NotPaused => write!(f, "state machine is not paused"),
+            Error::UnknownState(state) => write!(f, "no handler for state {state:?}"),
+            Error::NoRuntime => write!(f, "state machine has no executor to run on"),
+            Error::HandlerPanicked(state) => write!(f, "handler for state {state:?} panicked"),
+            Error::OutsideRegion { from, to } => {
+                write!(f, "state {from:?} cannot leave its region for {to:?}")
+            }
+            Error::Timeout => write!(f, "timed out waiting for the state machine"),
+            Error::Checkpoint(error) => write!(f, "{error}"),
+            Error::Journal(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
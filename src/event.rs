@@ -0,0 +1,191 @@
+//! Events published by a StateMachine
+//!
+//! `subscribe()` returns a receiver of every [`MachineEvent`] from then on: one
+//! [`TransitionEvent`] per tick, plus the lifecycle changes of the machine. The async
+//! machine hands out a [`futures::Stream`] and the blocking machine an
+//! [`std::sync::mpsc::Receiver`].
+//!
+//! Each subscriber has its own bounded buffer, so a slow subscriber never holds up the
+//! machine or the other subscribers. Events that do not fit are dropped, and the
+//! subscriber is told how many it missed with [`MachineEvent::Lagged`] once it has
+//! room again.
+//!
+//! ```rust
+//! use autostatemachine::{MachineEvent, StateMachineBuilder, StateMachineContext};
+//! use futures::StreamExt;
+//! # async fn run() {
+//! async fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let client = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build();
+//! let mut events = client.subscribe();
+//! client.run().await.unwrap();
+//! while let Some(event) = events.next().await {
+//!     if let MachineEvent::Transition(t) = event {
+//!         println!("{} -> {} in {:?}", t.from, t.to, t.duration_of_handler);
+//!     }
+//! }
+//! # }
+//! ```
+use std::time::{Duration, SystemTime};
+
+use crate::error::Error;
+use crate::state::StateName;
+
+/// How many events a subscriber buffers unless told otherwise
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// A tick whose handler ran and returned the next state
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransitionEvent {
+    pub from: StateName,
+    pub to: StateName,
+    /// Number of the tick over the life of the machine, starting from 1
+    pub tick: u64,
+    /// When the handler returned
+    pub at: SystemTime,
+    pub duration_of_handler: Duration,
+}
+
+/// Something from outside the machine that was recorded with `record_external()`,
+/// such as a message that influenced its handlers
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExternalEvent {
+    pub name: String,
+    pub data: String,
+}
+
+/// Something that happened to a StateMachine
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "kind", content = "data", rename_all = "snake_case")
+)]
+pub enum MachineEvent {
+    Transition(TransitionEvent),
+    /// A run loop was started
+    Started,
+    Paused,
+    Resumed,
+    /// The run loop exited and every `on_stop` hook has run
+    Stopped,
+    /// The run loop stopped itself because of the error. Followed by `Stopped`.
+    Failed(Error),
+    /// The subscriber fell behind and missed this many events
+    Lagged(u64),
+    /// A checkpoint could not be written. The machine keeps running.
+    CheckpointFailed(Error),
+    External(ExternalEvent),
+}
+
+/// Why an event could not be handed to a subscriber
+pub(crate) enum SendError {
+    Full,
+    Closed,
+}
+
+/// The sending half of a subscription
+pub(crate) trait EventSender: Send {
+    fn try_send(&mut self, event: MachineEvent) -> Result<(), SendError>;
+}
+
+impl EventSender for futures::channel::mpsc::Sender<MachineEvent> {
+    fn try_send(&mut self, event: MachineEvent) -> Result<(), SendError> {
+        futures::channel::mpsc::Sender::try_send(self, event).map_err(|e| {
+            if e.is_full() {
+                SendError::Full
+            } else {
+                SendError::Closed
+            }
+        })
+    }
+}
+
+impl EventSender for std::sync::mpsc::SyncSender<MachineEvent> {
+    fn try_send(&mut self, event: MachineEvent) -> Result<(), SendError> {
+        std::sync::mpsc::SyncSender::try_send(self, event).map_err(|e| match e {
+            std::sync::mpsc::TrySendError::Full(_) => SendError::Full,
+            std::sync::mpsc::TrySendError::Disconnected(_) => SendError::Closed,
+        })
+    }
+}
+
+struct Subscriber {
+    sender: Box<dyn EventSender>,
+    /// Events dropped since the last one that was delivered
+    missed: u64,
+}
+
+impl Subscriber {
+    /// Hand `event` over, reporting missed events first. Returns whether the
+    /// subscriber is still listening.
+    fn send(&mut self, event: MachineEvent) -> bool {
+        if self.missed > 0 {
+            match self.sender.try_send(MachineEvent::Lagged(self.missed)) {
+                Ok(()) => self.missed = 0,
+                Err(SendError::Full) => {
+                    self.missed += 1;
+                    return true;
+                }
+                Err(SendError::Closed) => return false,
+            }
+        }
+        match self.sender.try_send(event) {
+            Ok(()) => true,
+            Err(SendError::Full) => {
+                self.missed += 1;
+                true
+            }
+            Err(SendError::Closed) => false,
+        }
+    }
+}
+
+/// Everyone subscribed to a machine
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    pub(crate) fn add(&mut self, sender: impl EventSender + 'static) {
+        self.subscribers.push(Subscriber {
+            sender: Box::new(sender),
+            missed: 0,
+        });
+    }
+    /// Hand `event` to every subscriber, forgetting the ones that stopped listening
+    pub(crate) fn publish(&mut self, event: MachineEvent) {
+        self.subscribers
+            .retain_mut(|subscriber| subscriber.send(event.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lag_is_reported() {
+        let mut subscribers = Subscribers::default();
+        let (tx, rx) = std::sync::mpsc::sync_channel(2);
+        subscribers.add(tx);
+        for _ in 0..5 {
+            subscribers.publish(MachineEvent::Paused);
+        }
+        assert_eq!(rx.try_recv(), Ok(MachineEvent::Paused));
+        assert_eq!(rx.try_recv(), Ok(MachineEvent::Paused));
+        subscribers.publish(MachineEvent::Resumed);
+        assert_eq!(rx.try_recv(), Ok(MachineEvent::Lagged(3)));
+        assert_eq!(rx.try_recv(), Ok(MachineEvent::Resumed));
+        drop(rx);
+        subscribers.publish(MachineEvent::Stopped);
+        assert!(subscribers.subscribers.is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use crate::context::StateMachineContext;
+pub use crate::history::History;
 
 pub struct TickRate(pub Duration);
 impl<S> FromContext<S> for TickRate {
@@ -0,0 +1,157 @@
+//! Thread-safe, local and blocking flavors of the StateMachine
+//!
+//! Every async machine type takes a flavor parameter that defaults to [`Threaded`]:
+//! handlers, their futures and the user context must be `Send + Sync`, and the run loop
+//! can be spawned on any [`Executor`]. The [`Local`] flavor lifts those requirements so
+//! `Rc`-based libraries and single-threaded clients can be used from handlers, at the
+//! cost of running on a [`LocalExecutor`] such as a tokio `LocalSet`.
+//!
+//! Local machines are built with `StateMachineBuilder::new_local` and take the same
+//! extractors as threaded ones. The machines of the [`blocking`](crate::blocking)
+//! module are the [`Blocking`] flavor of the same core, whose handlers return
+//! straight away.
+//!
+//! ```rust
+//! use autostatemachine::{extractor::State, LocalStateMachine, StateMachineBuilder};
+//! use std::{cell::Cell, rc::Rc};
+//! # async fn run() {
+//! async fn count(State(count): State<Rc<Cell<u32>>>) -> String {
+//!     count.set(count.get() + 1);
+//!     "count".to_string()
+//! }
+//! let client: LocalStateMachine<_> = StateMachineBuilder::new_local(Rc::new(Cell::new(0)))
+//!     .add_state("count".to_string(), count)
+//!     .initial_state("count".to_string())
+//!     .build();
+//! tokio::task::LocalSet::new()
+//!     .run_until(async {
+//!         client.run().await.unwrap();
+//!         client.stop().await;
+//!     })
+//!     .await;
+//! # }
+//! ```
+use std::{
+    future::{ready, Future, Ready},
+    sync::Arc,
+};
+
+use futures::future::{BoxFuture, LocalBoxFuture};
+
+use crate::blocking::callback::Callback as BlockingCallback;
+use crate::callback::{Callback, LocalCallback};
+use crate::context::StateMachineContext;
+use crate::runtime::{Executor, LocalExecutor};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// How callbacks are stored and where the run loop may be spawned. Implemented by
+/// [`Threaded`], [`Local`] and [`Blocking`] only.
+pub trait Flavor<S>: sealed::Sealed + Sized + 'static {
+    /// The trait object handlers and hooks are stored as
+    type Callback<O: 'static>: ?Sized;
+    /// The future a callback returns, borrowing the contexts it was called with
+    type Future<'a, O: 'static>: Future<Output = O> + Unpin + 'a
+    where
+        S: 'a;
+    /// The trait object the run loop is spawned with
+    type Executor: ?Sized;
+    #[doc(hidden)]
+    fn call<'a, O: 'static>(
+        callback: &'a Self::Callback<O>,
+        context: &'a StateMachineContext,
+        s: &'a mut S,
+    ) -> Self::Future<'a, O>;
+    #[doc(hidden)]
+    fn default_executor() -> Option<Arc<Self::Executor>>;
+    #[doc(hidden)]
+    fn blocking_executor(executor: &Arc<Self::Executor>) -> Option<Arc<dyn Executor>>;
+}
+
+/// Machines whose handlers, futures and user context can move between threads
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Threaded;
+
+/// Machines that stay on the thread they were started from
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Local;
+
+/// Machines of the [`blocking`](crate::blocking) module, whose handlers are synchronous
+/// and whose run loop has a thread of its own
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blocking;
+
+impl sealed::Sealed for Threaded {}
+impl sealed::Sealed for Local {}
+impl sealed::Sealed for Blocking {}
+
+impl<S: 'static> Flavor<S> for Threaded {
+    type Callback<O: 'static> = dyn Callback<S, O>;
+    type Future<'a, O: 'static>
+        = BoxFuture<'a, O>
+    where
+        S: 'a;
+    type Executor = dyn Executor;
+    fn call<'a, O: 'static>(
+        callback: &'a Self::Callback<O>,
+        context: &'a StateMachineContext,
+        s: &'a mut S,
+    ) -> Self::Future<'a, O> {
+        callback.call(context, s)
+    }
+    fn default_executor() -> Option<Arc<Self::Executor>> {
+        crate::runtime::Runtime::default_for_features().map(|runtime| runtime.executor)
+    }
+    fn blocking_executor(executor: &Arc<Self::Executor>) -> Option<Arc<dyn Executor>> {
+        Some(executor.clone())
+    }
+}
+
+impl<S: 'static> Flavor<S> for Local {
+    type Callback<O: 'static> = dyn LocalCallback<S, O>;
+    type Future<'a, O: 'static>
+        = LocalBoxFuture<'a, O>
+    where
+        S: 'a;
+    type Executor = dyn LocalExecutor;
+    fn call<'a, O: 'static>(
+        callback: &'a Self::Callback<O>,
+        context: &'a StateMachineContext,
+        s: &'a mut S,
+    ) -> Self::Future<'a, O> {
+        callback.call(context, s)
+    }
+    fn default_executor() -> Option<Arc<Self::Executor>> {
+        #[cfg(feature = "tokio")]
+        return Some(Arc::new(crate::runtime::TokioLocal));
+        #[cfg(not(feature = "tokio"))]
+        return None;
+    }
+    fn blocking_executor(_: &Arc<Self::Executor>) -> Option<Arc<dyn Executor>> {
+        None
+    }
+}
+
+impl<S: 'static> Flavor<S> for Blocking {
+    type Callback<O: 'static> = dyn BlockingCallback<S, O>;
+    type Future<'a, O: 'static>
+        = Ready<O>
+    where
+        S: 'a;
+    type Executor = dyn Executor;
+    fn call<'a, O: 'static>(
+        callback: &'a Self::Callback<O>,
+        context: &'a StateMachineContext,
+        s: &'a mut S,
+    ) -> Self::Future<'a, O> {
+        ready(callback.call(context, s))
+    }
+    fn default_executor() -> Option<Arc<Self::Executor>> {
+        None
+    }
+    fn blocking_executor(_: &Arc<Self::Executor>) -> Option<Arc<dyn Executor>> {
+        None
+    }
+}
@@ -0,0 +1,581 @@
+//! Shareable handle for controlling a StateMachine
+//!
+//! A [`StateMachineHandle`] is cheap to clone and can be moved into as many tasks as
+//! needed, so a machine can be paused from an HTTP endpoint and stopped from a signal
+//! handler without wrapping the machine in a mutex.
+//!
+//! ```rust
+//! use autostatemachine::{StateMachineBuilder, StateMachineContext};
+//! # async fn run() {
+//! async fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let client = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build();
+//! let handle = client.handle();
+//! tokio::spawn(async move {
+//!     handle.pause().await;
+//! });
+//! # }
+//! ```
+use event_listener::{Event, EventListener, Listener};
+use futures::future::Either;
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::blocking_handle::BlockingHandle;
+#[cfg(feature = "checkpoint")]
+use crate::checkpoint::Checkpointer;
+use crate::context::{LifeCycle, StateMachineContext, StopMode, UserContext};
+use crate::error::Error;
+use crate::event::{ExternalEvent, MachineEvent};
+use crate::flavor::{Flavor, Local, Threaded};
+use crate::hierarchy::Hooks;
+use crate::history::History;
+use crate::machine::{self, Config, Control, Next, Parts};
+use crate::runtime::{Runtime, Timer};
+#[cfg(feature = "serde")]
+use crate::snapshot::MachineSnapshot;
+use crate::state::{States, Target};
+use crate::stats::MachineStats;
+use crate::step::{call_entered, call_exited, call_handler};
+use crate::transition::{Transition, TransitionOutcome};
+
+/// Everything the builder collects to construct a machine
+pub(crate) type MachineConfig<S, F = Threaded> =
+    Config<Box<<F as Flavor<S>>::Callback<Target>>, Box<<F as Flavor<S>>::Callback<()>>, S>;
+
+/// What the builder collects before it is checked
+pub(crate) type MachineParts<S, F = Threaded> =
+    Parts<Box<<F as Flavor<S>>::Callback<Target>>, Box<<F as Flavor<S>>::Callback<()>>, S>;
+
+/// How a caller of the shared core waits for the machine
+pub(crate) enum Sleep {
+    /// Await, measuring deadlines with the timer. Without one only waits without a
+    /// deadline are possible.
+    Async(Option<Arc<dyn Timer>>),
+    /// Block the calling thread
+    Thread,
+}
+
+impl Sleep {
+    /// Whether deadlines can be measured from the calling thread
+    fn can_time(&self) -> bool {
+        match self {
+            Sleep::Async(timer) => timer.as_ref().is_some_and(|timer| timer.is_available()),
+            Sleep::Thread => true,
+        }
+    }
+    /// Wait until `listener` is notified or `deadline` passes, returning whether it was
+    /// notified
+    pub(crate) async fn until(&self, listener: EventListener, deadline: Option<Instant>) -> bool {
+        match (self, deadline) {
+            (Sleep::Async(Some(timer)), Some(deadline)) => {
+                let woken = futures::future::select(listener, timer.sleep_until(deadline)).await;
+                matches!(woken, Either::Left(_))
+            }
+            (Sleep::Async(_), _) => {
+                listener.await;
+                true
+            }
+            (Sleep::Thread, Some(deadline)) => listener.wait_deadline(deadline).is_some(),
+            (Sleep::Thread, None) => {
+                listener.wait();
+                true
+            }
+        }
+    }
+}
+
+/// State shared between every handle of a machine and its run loop, whatever its
+/// flavor
+pub(crate) struct Shared<S, F: Flavor<S>> {
+    pub(crate) handlers: States<Box<F::Callback<Target>>>,
+    pub(crate) on_stop: Vec<Box<F::Callback<()>>>,
+    pub(crate) hooks: Hooks<Box<F::Callback<()>>>,
+    pub(crate) tick_rate: Duration,
+    /// Mode used by `stop()`
+    pub(crate) stop_mode: StopMode,
+    /// Never held across an `.await`
+    pub(crate) control: Mutex<Control>,
+    /// Notified when the lifecycle changes, so the run loop never polls for it
+    pub(crate) wake: Event,
+    /// Notified when the active run loop exits
+    pub(crate) exited: Event,
+    /// Where the run loop is spawned and how it sleeps
+    pub(crate) runtime: Option<Runtime<F::Executor>>,
+    #[cfg(feature = "checkpoint")]
+    pub(crate) checkpointer: Option<Checkpointer<S>>,
+    /// Lent to the handler for the duration of every tick, which also keeps ticks from
+    /// overlapping
+    pub(crate) user_context: futures::lock::Mutex<S>,
+}
+
+impl<S, F: Flavor<S>> Shared<S, F> {
+    pub(crate) fn new(config: MachineConfig<S, F>, runtime: Option<Runtime<F::Executor>>) -> Self {
+        Self {
+            control: Mutex::new(Control::new(&config)),
+            handlers: config.handlers,
+            on_stop: config.on_stop,
+            hooks: config.hooks,
+            tick_rate: config.tick_rate,
+            stop_mode: config.stop_mode,
+            wake: Event::new(),
+            exited: Event::new(),
+            runtime,
+            #[cfg(feature = "checkpoint")]
+            checkpointer: config.checkpoint,
+            user_context: futures::lock::Mutex::new(config.user_context),
+        }
+    }
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Control> {
+        self.control.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Stream every event from now on, buffering up to `capacity` of them (at least one)
+    pub(crate) fn subscribe(
+        &self,
+        capacity: usize,
+    ) -> futures::channel::mpsc::Receiver<MachineEvent> {
+        // The channel holds one event per sender on top of its buffer
+        let (tx, rx) = futures::channel::mpsc::channel(capacity.max(1) - 1);
+        self.lock().subscribe(tx);
+        rx
+    }
+    /// Write a checkpoint if one is due, or `always`. Called with the user context
+    /// locked.
+    #[cfg(feature = "checkpoint")]
+    async fn checkpoint(&self, user_context: &S, always: bool) {
+        if let Some(checkpointer) = &self.checkpointer {
+            checkpointer
+                .checkpoint(&self.control, user_context, always)
+                .await;
+        }
+    }
+    /// Run the handler for the current state once, if `allowed` accepts the lifecycle
+    /// at the moment the tick starts. Ticks never overlap, whether they come from the
+    /// run loop or from stepping.
+    pub(crate) async fn tick_if(
+        &self,
+        allowed: fn(&LifeCycle) -> bool,
+    ) -> Option<TransitionOutcome> {
+        let mut user_context = self.user_context.lock().await;
+        // The handler borrows a snapshot, as the control lock cannot be held across it
+        let context = self.lock().start_tick(allowed)?;
+        if !std::mem::replace(&mut self.lock().entered, true) {
+            call_entered::<S, F>(&self.hooks, &context, &mut user_context).await;
+        }
+        // Ticks are counted under the user context lock, so this is the tick starting
+        #[cfg(feature = "tracing")]
+        let span = self.lock().handler_span();
+        let started = Instant::now();
+        let handler =
+            call_handler::<S, F>(&self.handlers, &self.hooks, &context, &mut user_context);
+        #[cfg(feature = "tracing")]
+        let handler = tracing::Instrument::instrument(handler, span);
+        let tick = handler.await;
+        // Released first, so recording the transition does not copy the history
+        drop(context);
+        self.lock().finish_tick(&tick, started);
+        #[cfg(feature = "checkpoint")]
+        if let TransitionOutcome::Transitioned(_) = tick.outcome {
+            self.checkpoint(&user_context, false).await;
+        }
+        Some(tick.outcome)
+    }
+    /// Publish that `name` happened outside the machine
+    pub(crate) fn record_external(&self, name: &str, data: &str) {
+        self.lock().external(ExternalEvent {
+            name: name.to_string(),
+            data: data.to_string(),
+        });
+    }
+    /// Where the machine is, with a clone of its user context taken between ticks
+    #[cfg(feature = "serde")]
+    pub(crate) async fn snapshot_with_context(&self) -> MachineSnapshot<S>
+    where
+        S: Clone,
+    {
+        let user_context = self.user_context.lock().await;
+        let mut snapshot = self.lock().snapshot();
+        snapshot.user_context = Some(user_context.clone());
+        snapshot
+    }
+    /// Switch between running and paused and wake the loop to notice immediately
+    pub(crate) fn set_life_cycle(&self, life_cycle: LifeCycle) {
+        if self.lock().set_life_cycle(life_cycle) {
+            self.wake.notify(usize::MAX);
+        }
+    }
+    /// Tell the run loop to stop without waiting for it to exit. Returns whether a
+    /// loop was active to receive the request.
+    pub(crate) fn request_stop(&self, mode: StopMode) -> bool {
+        let stopping = self.lock().request_stop(mode);
+        if stopping {
+            self.wake.notify(usize::MAX);
+        }
+        stopping
+    }
+    /// Stop the machine with `mode` and wait until the run loop has exited
+    pub(crate) async fn stop_with(&self, sleep: &Sleep, mode: StopMode) {
+        let exited = self.exited.listen();
+        if self.request_stop(mode) {
+            sleep.until(exited, None).await;
+        } else if mode == StopMode::Reset {
+            self.lock().reset();
+        }
+    }
+    /// Run exactly one tick of a paused machine
+    pub(crate) async fn step(&self) -> Result<Transition, Error> {
+        machine::step_result(self.tick_if(machine::is_paused).await)
+    }
+    /// Run `n` ticks of a paused machine, one after another
+    pub(crate) async fn run_ticks(&self, n: usize) -> Result<Vec<Transition>, Error> {
+        let mut transitions = Vec::with_capacity(n);
+        for _ in 0..n {
+            transitions.push(self.step().await?);
+        }
+        Ok(transitions)
+    }
+    /// Run ticks of a paused machine until `predicate` holds for its context
+    pub(crate) async fn run_until<P>(&self, mut predicate: P) -> Result<Vec<Transition>, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        let mut transitions = Vec::new();
+        while !predicate(&self.lock().context) {
+            transitions.push(self.step().await?);
+        }
+        Ok(transitions)
+    }
+    /// Wait until `predicate` holds for the live context, or `timeout` passes
+    pub(crate) async fn wait_until<P>(
+        &self,
+        sleep: &Sleep,
+        mut predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        if timeout.is_some() && !sleep.can_time() {
+            return Err(Error::NoRuntime);
+        }
+        let deadline = timeout.map(|timeout| (timeout, Instant::now() + timeout));
+        loop {
+            let changed = match self.lock().watch(&mut predicate) {
+                Ok(context) => return Ok(context),
+                Err(changed) => changed,
+            };
+            if !sleep.until(changed, deadline.map(|(_, at)| at)).await {
+                let (timeout, _) = deadline.expect("only a deadline can pass");
+                return Err(self.lock().timed_out(timeout));
+            }
+        }
+    }
+    /// Claim the run loop for a new run, waiting for a stopped loop that has not exited
+    /// yet
+    pub(crate) async fn claim_loop(
+        self: &Arc<Self>,
+        sleep: &Sleep,
+    ) -> Result<LoopGuard<S, F>, Error> {
+        loop {
+            // Register interest before checking so an exit in between is not missed
+            let exited = self.exited.listen();
+            if self.lock().claim_loop(self.stop_mode)? {
+                break;
+            }
+            sleep.until(exited, None).await;
+        }
+        Ok(LoopGuard {
+            shared: self.clone(),
+        })
+    }
+}
+
+/// Marks the run loop as exited however it ends, including a panicking handler
+pub(crate) struct LoopGuard<S, F: Flavor<S>> {
+    shared: Arc<Shared<S, F>>,
+}
+
+impl<S, F: Flavor<S>> Drop for LoopGuard<S, F> {
+    fn drop(&mut self) {
+        self.shared.lock().release_loop();
+        self.shared.exited.notify(usize::MAX);
+    }
+}
+
+/// The run loop of a machine of any flavor, until it is stopped and its hooks have run
+pub(crate) async fn run_loop<S, F: Flavor<S>>(guard: LoopGuard<S, F>, sleep: Sleep) {
+    let shared = guard.shared.clone();
+    let mut next_tick = Instant::now();
+    let stopped_context = loop {
+        // Listen before checking so a control call in between is not missed
+        let woken = shared.wake.listen();
+        let next = shared.lock().next(next_tick);
+        match next {
+            Next::Stop(context) => break context,
+            Next::Tick => {
+                // Paused or stopped since the check above: decided next iteration
+                let outcome = shared.tick_if(machine::is_running).await;
+                if let Some(Err(error)) = outcome.map(machine::tick_result) {
+                    // Nothing can run from here, so the machine stops itself
+                    break shared.lock().fail(error);
+                }
+                next_tick = Instant::now() + shared.lock().context.tick_rate;
+            }
+            Next::Until(deadline) => {
+                sleep.until(woken, Some(deadline)).await;
+            }
+            Next::Parked => {
+                sleep.until(woken, None).await;
+            }
+        }
+    };
+    // Hooks see the context as it was when the machine stopped
+    {
+        let mut user_context = shared.user_context.lock().await;
+        if shared.lock().entered {
+            call_exited::<S, F>(&shared.hooks, &stopped_context, &mut user_context).await;
+        }
+        for hook in shared.on_stop.iter() {
+            F::call(hook, &stopped_context, &mut user_context).await;
+        }
+    }
+    shared.lock().finish_stop();
+    // Last, so it has the state the machine restarts from
+    #[cfg(feature = "checkpoint")]
+    shared
+        .checkpoint(&*shared.user_context.lock().await, true)
+        .await;
+    drop(guard);
+}
+
+/// Cloneable handle exposing the control and query methods of a StateMachine
+pub struct StateMachineHandle<S, F: Flavor<S> = Threaded> {
+    pub(crate) shared: Arc<Shared<S, F>>,
+}
+
+impl<S, F: Flavor<S>> Clone for StateMachineHandle<S, F> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S, F> StateMachineHandle<S, F>
+where
+    S: 'static,
+    F: Flavor<S>,
+{
+    pub(crate) fn new(config: MachineConfig<S, F>, runtime: Option<Runtime<F::Executor>>) -> Self {
+        Self {
+            shared: Arc::new(Shared::new(config, runtime)),
+        }
+    }
+    /// How waits on this handle sleep
+    fn sleep(&self) -> Sleep {
+        Sleep::Async(
+            self.shared
+                .runtime
+                .as_ref()
+                .map(|runtime| runtime.timer.clone()),
+        )
+    }
+    pub async fn get_context(&self) -> StateMachineContext {
+        StateMachineContext::clone(&self.shared.lock().context)
+    }
+    /// The most recent transitions, oldest first
+    pub async fn get_history(&self) -> History {
+        self.shared.lock().context.history.clone()
+    }
+    /// Statistics of every tick so far. See the [`stats`](crate::stats) module.
+    pub async fn get_stats(&self) -> MachineStats {
+        self.shared.lock().stats.snapshot()
+    }
+    /// Publish that `name` happened outside the machine, such as a message that its
+    /// handlers will act on, so journals record it for replay. See the
+    /// [`journal`](crate::journal) module.
+    pub async fn record_external(&self, name: &str, data: &str) {
+        self.shared.record_external(name, data);
+    }
+    /// Where the machine is, without its user context. See the
+    /// [`snapshot`](crate::snapshot) module.
+    #[cfg(feature = "serde")]
+    pub async fn snapshot(&self) -> MachineSnapshot<S> {
+        self.shared.lock().snapshot()
+    }
+    /// Where the machine is, with a clone of its user context. Waits for a tick in
+    /// progress to finish, so both are taken at the same point.
+    #[cfg(feature = "serde")]
+    pub async fn snapshot_with_context(&self) -> MachineSnapshot<S>
+    where
+        S: Clone,
+    {
+        self.shared.snapshot_with_context().await
+    }
+    /// Lock the user context, waiting for a tick in progress to finish. Must not be
+    /// awaited from inside a handler or hook of the same machine.
+    pub async fn get_user_context(&self) -> UserContext<'_, S> {
+        UserContext(self.shared.user_context.lock().await)
+    }
+    pub fn get_tick_rate(&self) -> &Duration {
+        &self.shared.tick_rate
+    }
+    /// Stream every [`MachineEvent`] from now on, buffering up to
+    /// [`DEFAULT_CAPACITY`](crate::event::DEFAULT_CAPACITY) of them. See the
+    /// [`event`](crate::event) module.
+    pub fn subscribe(&self) -> futures::channel::mpsc::Receiver<MachineEvent> {
+        self.subscribe_with_capacity(crate::event::DEFAULT_CAPACITY)
+    }
+    /// Stream every [`MachineEvent`] from now on, buffering up to `capacity` of them
+    /// (at least one) before the subscriber lags
+    pub fn subscribe_with_capacity(
+        &self,
+        capacity: usize,
+    ) -> futures::channel::mpsc::Receiver<MachineEvent> {
+        self.shared.subscribe(capacity)
+    }
+    /// Whether a run loop is currently active, including one that has been told to
+    /// stop but has not exited yet
+    pub fn is_running(&self) -> bool {
+        self.shared.lock().loop_active
+    }
+    /// Pause a running machine. Has no effect if the machine is stopped.
+    pub async fn pause(&self) {
+        self.shared.set_life_cycle(LifeCycle::Paused);
+    }
+    /// Resume a paused machine. Has no effect if the machine is stopped.
+    pub async fn resume(&self) {
+        self.shared.set_life_cycle(LifeCycle::Running);
+    }
+    /// Stop the machine using the builder's [`StopMode`] (`StopMode::Reset` unless
+    /// configured otherwise). See [`stop_with`](Self::stop_with).
+    pub async fn stop(&self) {
+        self.stop_with(self.shared.stop_mode).await
+    }
+    /// Stop the machine and wait until the run loop has exited and every `on_stop`
+    /// hook has run. `mode` decides whether `current_state` is reset to the initial
+    /// state or preserved for the next `run()`.
+    ///
+    /// Must not be awaited from inside a handler or hook of the same machine, as the
+    /// loop cannot exit until that handler returns.
+    pub async fn stop_with(&self, mode: StopMode) {
+        self.shared.stop_with(&self.sleep(), mode).await
+    }
+    /// Run exactly one tick of a paused machine and return the transition it made.
+    ///
+    /// Returns [`Error::NotPaused`] unless the machine is running and paused,
+    /// [`Error::UnknownState`] if the current state has no handler, and
+    /// [`Error::OutsideRegion`] if a region of a parallel state returned a state outside it.
+    pub async fn step(&self) -> Result<Transition, Error> {
+        self.shared.step().await
+    }
+    /// Run `n` ticks of a paused machine, one after another, returning every transition.
+    pub async fn run_ticks(&self, n: usize) -> Result<Vec<Transition>, Error> {
+        self.shared.run_ticks(n).await
+    }
+    /// Run ticks of a paused machine until `predicate` holds for its context, returning
+    /// every transition made. Runs no ticks if the predicate already holds.
+    pub async fn run_until<P>(&self, predicate: P) -> Result<Vec<Transition>, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        self.shared.run_until(predicate).await
+    }
+    /// Wait until the machine is in `state`, or in one of its children if it is a
+    /// compound state. See [`wait_until`](Self::wait_until).
+    pub async fn wait_for_state(
+        &self,
+        state: &str,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error> {
+        self.wait_until(|context| context.is_in(state), timeout)
+            .await
+    }
+    /// Wait until `predicate` holds for the live context and return that context. The
+    /// predicate is checked again whenever the context changes, so this resolves as
+    /// soon as it holds, and straight away if it already does.
+    ///
+    /// Returns [`Error::Timeout`] if `timeout` passes first, and [`Error::NoRuntime`] if
+    /// a timeout is given to a machine without a timer to measure it, or from outside
+    /// the runtime its timer needs.
+    pub async fn wait_until<P>(
+        &self,
+        predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        self.shared
+            .wait_until(&self.sleep(), predicate, timeout)
+            .await
+    }
+    /// See [`Shared::request_stop`]
+    pub(crate) fn request_stop(&self, mode: StopMode) -> bool {
+        self.shared.request_stop(mode)
+    }
+    /// Claim the run loop for a new run, returning the runtime to spawn it on
+    async fn start_run(&self) -> Result<(Runtime<F::Executor>, LoopGuard<S, F>), Error> {
+        let runtime = self.shared.runtime.clone().ok_or(Error::NoRuntime)?;
+        if !runtime.timer.is_available() {
+            return Err(Error::NoRuntime);
+        }
+        let guard = self.shared.claim_loop(&Sleep::Async(None)).await?;
+        Ok((runtime, guard))
+    }
+}
+
+impl<S> StateMachineHandle<S>
+where
+    S: Send + Sync + 'static,
+{
+    /// Start the run loop.
+    ///
+    /// Only one run loop may be active at a time: if the machine is running or paused
+    /// this returns [`Error::AlreadyRunning`]. If it has been stopped but the previous
+    /// loop has not exited yet, this waits for that loop to exit before starting again.
+    ///
+    /// Returns [`Error::NoRuntime`] if the machine was built without a runtime feature
+    /// enabled and without calling `StateMachineBuilder::runtime`, or if its runtime
+    /// cannot be used from the calling thread, such as the default tokio runtime from
+    /// outside tokio.
+    pub async fn run(&self) -> Result<(), Error> {
+        let available = |runtime: &Runtime| runtime.executor.is_available();
+        if !self.shared.runtime.as_ref().is_some_and(available) {
+            return Err(Error::NoRuntime);
+        }
+        let (runtime, guard) = self.start_run().await?;
+        runtime
+            .executor
+            .spawn(Box::pin(run_loop(guard, Sleep::Async(Some(runtime.timer)))));
+        Ok(())
+    }
+    /// Get a handle exposing the same methods synchronously, for threads outside the
+    /// runtime. See [`BlockingHandle`].
+    pub fn blocking_handle(&self) -> BlockingHandle<S> {
+        BlockingHandle::new(self.clone())
+    }
+}
+
+impl<S> StateMachineHandle<S, Local>
+where
+    S: 'static,
+{
+    /// Start the run loop with `spawn_local`. Behaves like the threaded
+    /// [`run`](StateMachineHandle::run), but must be called from inside the
+    /// [`LocalExecutor`](crate::runtime::LocalExecutor) the machine was built with,
+    /// such as a tokio `LocalSet`.
+    pub async fn run(&self) -> Result<(), Error> {
+        let (runtime, guard) = self.start_run().await?;
+        runtime
+            .executor
+            .spawn_local(Box::pin(run_loop(guard, Sleep::Async(Some(runtime.timer)))));
+        Ok(())
+    }
+}
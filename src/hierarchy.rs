@@ -0,0 +1,689 @@
+//! Compound states and their entry and exit hooks
+//!
+//! The builder's `compound_state(name, initial_child, children)` makes a state contain
+//! others. Entering a compound state enters its initial child, all the way down, so
+//! `current_state` is always a state without children, and
+//! [`StateName::path`](crate::StateName::path) lists the compound states it is in.
+//!
+//! Each tick runs the handlers along that path, outermost first. A compound state
+//! returning its own name leaves the tick to its active child, while any other state
+//! it returns is transitioned to without running the child, which lets a parent
+//! handle what is common to all of its children. A compound state needs no handler
+//! if it never intercepts.
+//!
+//! Hooks added with `on_exit(state, f)` and `on_enter(state, f)` run when a transition
+//! leaves or enters `state`, after the handler and before the next tick. The states
+//! left are exited innermost first, then the states entered are entered outermost
+//! first. States containing both ends of the transition are neither, and a state
+//! returning its own name stays without running either. The machine enters the states
+//! it starts in, outermost first, before the handlers of its first tick, and a run
+//! loop exits the states it stops in, innermost first, before the `on_stop` hooks.
+//!
+//! `parallel_state(name, regions)` makes a state whose regions, each a compound state,
+//! are all active at once. While in it, `current_state` is the parallel state and
+//! `regions` in the context holds the active state of each region. Once the parallel
+//! state defers, every region is ticked in the order given: a target inside the
+//! region moves only that region, and a target outside the parallel state leaves it
+//! and all of its regions. A target elsewhere in the parallel state is refused with
+//! `TransitionOutcome::OutsideRegion`, and like a state without a handler it leaves
+//! the machine where it was, without running any hook. The hooks of the regions run
+//! once every region has decided. `final_state(name)` marks states a region can
+//! finish in, and `join(name, to)` moves the machine to `to` in the tick where every
+//! region is in a final state. Regions cannot contain parallel states, at any depth,
+//! and building a machine where one does panics.
+//!
+//! ```rust
+//! use autostatemachine::blocking::{StateMachineBuilder, StateMachineContext};
+//! fn connected(_: &StateMachineContext, dropped: &mut bool) -> String {
+//!     match dropped {
+//!         true => "disconnected".to_string(),
+//!         false => "connected".to_string(),
+//!     }
+//! }
+//! fn handshake(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! fn disconnected(_: StateMachineContext) -> String {
+//!     "connected".to_string()
+//! }
+//! let mut machine = StateMachineBuilder::new(false)
+//!     .add_state("connected".to_string(), connected)
+//!     .add_state("handshake".to_string(), handshake)
+//!     .add_state("idle".to_string(), idle)
+//!     .add_state("disconnected".to_string(), disconnected)
+//!     .compound_state("connected", "handshake", &["handshake", "idle"])
+//!     .initial_state("connected".to_string())
+//!     .on_exit("connected", |_: StateMachineContext| println!("connection lost"))
+//!     .build_step_machine();
+//! let active: Vec<_> = machine.get_context().current_state.path().collect();
+//! assert_eq!(active, ["connected", "handshake"]);
+//! machine.step();
+//! *machine.get_user_context_mut() = true;
+//! machine.step();
+//! assert_eq!(machine.get_context().current_state, "disconnected");
+//! ```
+use std::{cmp::Reverse, collections::HashMap};
+
+use crate::machine::Tick;
+use crate::state::{StateName, States};
+use crate::transition::{Transition, TransitionOutcome};
+
+/// Hooks run when transitions enter or leave states, by state name
+pub(crate) struct Hooks<K> {
+    pub(crate) enter: HashMap<String, Vec<K>>,
+    pub(crate) exit: HashMap<String, Vec<K>>,
+}
+
+impl<K> Default for Hooks<K> {
+    fn default() -> Self {
+        Self {
+            enter: HashMap::new(),
+            exit: HashMap::new(),
+        }
+    }
+}
+
+impl<K> Hooks<K> {
+    /// The hooks to run when the active states go from those containing `from` to
+    /// those containing `to`, in order. Both hold the active states without children,
+    /// one per region in a parallel state.
+    pub(crate) fn crossed(&self, from: &[StateName], to: &[StateName]) -> Vec<&K> {
+        if self.enter.is_empty() && self.exit.is_empty() {
+            return Vec::new();
+        }
+        let (from, to) = (containing(from), containing(to));
+        let mut exits: Vec<_> = from.iter().filter(|state| !to.contains(state)).collect();
+        exits.sort_by_key(|state| Reverse(state.parents().len()));
+        let mut entries: Vec<_> = to.iter().filter(|state| !from.contains(state)).collect();
+        entries.sort_by_key(|state| state.parents().len());
+        let exits = exits
+            .into_iter()
+            .filter_map(|state| self.exit.get(state.as_str()));
+        let entries = entries
+            .into_iter()
+            .filter_map(|state| self.enter.get(state.as_str()));
+        exits.chain(entries).flatten().collect()
+    }
+    /// The entry hooks of the states the machine starts in, outermost first
+    pub(crate) fn entered(&self, active: &[StateName]) -> Vec<&K> {
+        self.crossed(&[], active)
+    }
+    /// The exit hooks of the states the machine stops in, innermost first
+    pub(crate) fn exited(&self, active: &[StateName]) -> Vec<&K> {
+        self.crossed(active, &[])
+    }
+}
+
+/// Every state active while `states` are, each once
+fn containing(states: &[StateName]) -> Vec<&StateName> {
+    let mut active = Vec::new();
+    for state in states.iter().flat_map(StateName::path) {
+        if !active.contains(&state) {
+            active.push(state);
+        }
+    }
+    active
+}
+
+/// The states without children that are active in `state`: its regions if it is a
+/// parallel state, and otherwise itself
+pub(crate) fn active<'a>(state: &'a StateName, regions: &'a [StateName]) -> &'a [StateName] {
+    match regions.is_empty() {
+        true => std::slice::from_ref(state),
+        false => regions,
+    }
+}
+
+/// What the handlers along a path decided
+pub(crate) enum Decision {
+    /// Enter this state
+    To(StateName),
+    /// The state has no handler
+    Unknown(StateName),
+    /// Every state returned its own name, leaving the tick to its children
+    Deferred,
+}
+
+/// The tick moving the machine from `from`, with `regions` active in it, to the state
+/// `to` entered by a handler, and the hooks to run for it
+pub(crate) fn transition<'k, H, K>(
+    handlers: &States<H>,
+    hooks: &'k Hooks<K>,
+    from: &StateName,
+    regions: &[StateName],
+    to: StateName,
+) -> (Tick, Vec<&'k K>) {
+    let entered = handlers.regions(&to);
+    let crossed = hooks.crossed(active(from, regions), active(&to, &entered));
+    let transition = Transition {
+        from: from.clone(),
+        to,
+    };
+    let tick = Tick {
+        outcome: TransitionOutcome::Transitioned(transition),
+        regions: entered,
+    };
+    (tick, crossed)
+}
+
+/// Whether `to` is inside the region of parallel state `parallel` that `state` is in
+pub(crate) fn in_region(parallel: &StateName, state: &StateName, to: &StateName) -> bool {
+    state
+        .path()
+        .nth(parallel.parents().len() + 1)
+        .is_some_and(|region| to.is_in(region))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blocking::{StateMachineBuilder, StateMachineContext};
+    use crate::transition::{Transition, TransitionOutcome};
+
+    #[derive(Default)]
+    struct Link {
+        dropped: bool,
+        log: Vec<String>,
+    }
+
+    fn connected(_: &StateMachineContext, link: &mut Link) -> String {
+        match link.dropped {
+            true => "disconnected".to_string(),
+            false => "connected".to_string(),
+        }
+    }
+    fn handshake(_: StateMachineContext) -> String {
+        "idle".to_string()
+    }
+    fn idle(_: StateMachineContext) -> String {
+        "busy".to_string()
+    }
+    fn busy(_: StateMachineContext) -> String {
+        "idle".to_string()
+    }
+    fn disconnected(_: StateMachineContext) -> String {
+        "connected".to_string()
+    }
+    fn builder() -> StateMachineBuilder<Link> {
+        let mut builder = StateMachineBuilder::new(Link::default())
+            .add_state("connected".to_string(), connected)
+            .add_state("handshake".to_string(), handshake)
+            .add_state("idle".to_string(), idle)
+            .add_state("busy".to_string(), busy)
+            .add_state("disconnected".to_string(), disconnected)
+            .compound_state("connected", "handshake", &["handshake", "session"])
+            .compound_state("session", "idle", &["idle", "busy"])
+            .initial_state("connected".to_string());
+        for state in ["connected", "handshake", "session", "idle", "busy"] {
+            builder = builder
+                .on_enter(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("enter {state}"))
+                })
+                .on_exit(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("exit {state}"))
+                });
+        }
+        builder
+    }
+    #[test]
+    fn test_nested_states() {
+        let mut machine = builder().build_step_machine();
+        let context = machine.get_context();
+        assert_eq!(context.current_state, "handshake");
+        assert!(context.current_state.is_in("connected"));
+        assert!(machine.get_user_context().log.is_empty());
+        // The first step enters the initial states, then entering a compound state
+        // enters its initial child
+        machine.step();
+        let active: Vec<_> = machine.get_context().current_state.path().collect();
+        assert_eq!(active, ["connected", "session", "idle"]);
+        machine.step();
+        assert_eq!(machine.get_context().current_state, "busy");
+        assert_eq!(
+            machine.get_user_context().log,
+            [
+                "enter connected",
+                "enter handshake",
+                "exit handshake",
+                "enter session",
+                "enter idle",
+                "exit idle",
+                "enter busy"
+            ]
+        );
+        // The parent intercepts whatever child is active
+        machine.get_user_context_mut().log.clear();
+        machine.get_user_context_mut().dropped = true;
+        assert_eq!(
+            machine.step(),
+            TransitionOutcome::Transitioned(Transition {
+                from: "busy".into(),
+                to: "disconnected".into()
+            })
+        );
+        assert_eq!(
+            machine.get_user_context().log,
+            ["exit busy", "exit session", "exit connected"]
+        );
+        machine.get_user_context_mut().dropped = false;
+        machine.step();
+        assert_eq!(machine.get_context().current_state, "handshake");
+    }
+    /// The same machine as [`builder`], on the async builder
+    fn async_builder() -> crate::StateMachineBuilder<Link> {
+        async fn connected(_: &StateMachineContext, link: &mut Link) -> String {
+            match link.dropped {
+                true => "disconnected".to_string(),
+                false => "connected".to_string(),
+            }
+        }
+        let mut builder = crate::StateMachineBuilder::new(Link::default())
+            .add_state("connected".to_string(), connected)
+            .add_state("handshake".to_string(), || async { "idle".to_string() })
+            .add_state("idle".to_string(), || async { "busy".to_string() })
+            .add_state("busy".to_string(), || async { "idle".to_string() })
+            .add_state("disconnected".to_string(), || async {
+                "connected".to_string()
+            })
+            .compound_state("connected", "handshake", &["handshake", "session"])
+            .compound_state("session", "idle", &["idle", "busy"])
+            .initial_state("connected".to_string());
+        for state in ["connected", "handshake", "session", "idle", "busy"] {
+            builder = builder
+                .on_enter(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("enter {state}"));
+                    async {}
+                })
+                .on_exit(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("exit {state}"));
+                    async {}
+                });
+        }
+        builder
+    }
+    #[test]
+    fn test_nested_states_async() {
+        let mut machine = async_builder().build_step_machine();
+        assert_eq!(machine.get_context().current_state, "handshake");
+        futures::executor::block_on(machine.step());
+        let active: Vec<_> = machine.get_context().current_state.path().collect();
+        assert_eq!(active, ["connected", "session", "idle"]);
+        futures::executor::block_on(machine.step());
+        assert_eq!(
+            machine.get_user_context().log,
+            [
+                "enter connected",
+                "enter handshake",
+                "exit handshake",
+                "enter session",
+                "enter idle",
+                "exit idle",
+                "enter busy"
+            ]
+        );
+        machine.get_user_context_mut().log.clear();
+        machine.get_user_context_mut().dropped = true;
+        assert_eq!(
+            futures::executor::block_on(machine.step()),
+            TransitionOutcome::Transitioned(Transition {
+                from: "busy".into(),
+                to: "disconnected".into()
+            })
+        );
+        assert_eq!(
+            machine.get_user_context().log,
+            ["exit busy", "exit session", "exit connected"]
+        );
+    }
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_nested_states_run_loop() {
+        let client = async_builder()
+            .tick_rate(std::time::Duration::from_millis(1))
+            .build();
+        client.run().await.unwrap();
+        client.wait_for_state("busy", None).await.unwrap();
+        client.pause().await;
+        let mut link = client.get_user_context().await;
+        link.log.clear();
+        link.dropped = true;
+        drop(link);
+        // The parent intercepts whichever child the loop was paused in
+        let transitions = client
+            .run_until(|context| !context.is_in("connected"))
+            .await
+            .unwrap();
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].to, "disconnected");
+        client.stop().await;
+        assert_eq!(
+            client.get_user_context().await.log,
+            [
+                format!("exit {}", transitions[0].from),
+                "exit session".to_string(),
+                "exit connected".to_string()
+            ]
+        );
+    }
+    /// What a run loop of [`builder`] logs for one tick, from starting to stopping
+    const ONE_TICK_RUN: [&str; 8] = [
+        "enter connected",
+        "enter handshake",
+        "exit handshake",
+        "enter session",
+        "enter idle",
+        "exit idle",
+        "exit session",
+        "exit connected",
+    ];
+    #[test]
+    fn test_run_loop_enters_and_exits() {
+        let client = builder()
+            .tick_rate(std::time::Duration::from_secs(10))
+            .build();
+        client.run().unwrap();
+        client.wait_for_state("idle", None).unwrap();
+        client.stop();
+        assert_eq!(client.get_user_context().log, ONE_TICK_RUN);
+        // Every run enters the states it starts in again
+        client.get_user_context().log.clear();
+        client.run().unwrap();
+        client.wait_for_state("idle", None).unwrap();
+        client.stop();
+        assert_eq!(client.get_user_context().log, ONE_TICK_RUN);
+    }
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_run_loop_enters_and_exits_async() {
+        let client = async_builder()
+            .tick_rate(std::time::Duration::from_secs(10))
+            .build();
+        client.run().await.unwrap();
+        client.wait_for_state("idle", None).await.unwrap();
+        client.stop().await;
+        assert_eq!(client.get_user_context().await.log, ONE_TICK_RUN);
+    }
+    #[test]
+    #[should_panic]
+    fn test_initial_child_outside() {
+        StateMachineBuilder::new(())
+            .add_state("idle".to_string(), idle)
+            .add_state("busy".to_string(), busy)
+            .compound_state("session", "busy", &["idle"])
+            .initial_state("session".to_string())
+            .build_step_machine();
+    }
+    #[test]
+    #[should_panic(expected = "Hooks were added to idel, which is not a state")]
+    fn test_hook_on_unknown_state() {
+        StateMachineBuilder::new(())
+            .add_state("idle".to_string(), idle)
+            .initial_state("idle".to_string())
+            .on_enter("idel", |_: StateMachineContext| println!("entered"))
+            .build_step_machine();
+    }
+    #[test]
+    #[should_panic(expected = "Parallel state inner is inside a region of outer")]
+    fn test_parallel_inside_region() {
+        StateMachineBuilder::new(())
+            .add_state("idle".to_string(), idle)
+            .add_state("busy".to_string(), busy)
+            .compound_state("left", "idle", &["idle"])
+            .compound_state("right", "busy", &["busy"])
+            .parallel_state("inner", &["left", "right"])
+            .add_state("other".to_string(), idle)
+            .compound_state("nested", "inner", &["inner"])
+            .compound_state("region", "nested", &["nested"])
+            .compound_state("second", "other", &["other"])
+            .parallel_state("outer", &["region", "second"])
+            .initial_state("outer".to_string())
+            .build_step_machine();
+    }
+    fn offline(_: &StateMachineContext, link: &mut Link) -> String {
+        match link.dropped {
+            true => "offline".to_string(),
+            false => "online".to_string(),
+        }
+    }
+    fn charging(_: StateMachineContext) -> String {
+        "full".to_string()
+    }
+    fn stay(context: StateMachineContext) -> String {
+        context.current_state.to_string()
+    }
+    #[test]
+    fn test_parallel_regions() {
+        let mut builder = StateMachineBuilder::new(Link {
+            dropped: true,
+            log: Vec::new(),
+        })
+        .add_state("offline".to_string(), offline)
+        .add_state("online".to_string(), |_: StateMachineContext| {
+            "online".to_string()
+        })
+        .add_state("charging".to_string(), charging)
+        .add_state("full".to_string(), |_: StateMachineContext| {
+            "full".to_string()
+        })
+        .add_state("ready".to_string(), stay)
+        .compound_state("connection", "offline", &["offline", "online"])
+        .compound_state("battery", "charging", &["charging", "full"])
+        .parallel_state("device", &["connection", "battery"])
+        .final_state("online")
+        .final_state("full")
+        .join("device", "ready")
+        .initial_state("device".to_string());
+        for state in ["device", "connection", "online", "offline", "full", "ready"] {
+            builder = builder
+                .on_enter(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("enter {state}"))
+                })
+                .on_exit(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("exit {state}"))
+                });
+        }
+        let mut machine = builder.build_step_machine();
+        let context = machine.get_context();
+        assert_eq!(context.current_state, "device");
+        assert_eq!(context.regions, ["offline", "charging"]);
+        // Every region ticks, and the machine stays until all of them are final
+        assert_eq!(
+            machine.step(),
+            TransitionOutcome::Transitioned(Transition {
+                from: "device".into(),
+                to: "device".into()
+            })
+        );
+        let context = machine.get_context();
+        assert_eq!(context.region("battery").unwrap(), "full");
+        assert!(context.is_in("connection") && context.is_in("full"));
+        assert_eq!(
+            machine.get_user_context().log,
+            [
+                "enter device",
+                "enter connection",
+                "enter offline",
+                "enter full"
+            ]
+        );
+        machine.get_user_context_mut().log.clear();
+        machine.get_user_context_mut().dropped = false;
+        assert_eq!(
+            machine.step(),
+            TransitionOutcome::Transitioned(Transition {
+                from: "device".into(),
+                to: "ready".into()
+            })
+        );
+        assert!(machine.get_context().regions.is_empty());
+        assert_eq!(
+            machine.get_user_context().log,
+            [
+                "exit offline",
+                "enter online",
+                "exit online",
+                "exit full",
+                "exit connection",
+                "exit device",
+                "enter ready"
+            ]
+        );
+    }
+    #[test]
+    fn test_parallel_regions_async() {
+        async fn offline(_: &StateMachineContext, link: &mut Link) -> String {
+            match link.dropped {
+                true => "offline".to_string(),
+                false => "online".to_string(),
+            }
+        }
+        let mut builder = crate::StateMachineBuilder::new(Link {
+            dropped: true,
+            log: Vec::new(),
+        })
+        .add_state("offline".to_string(), offline)
+        .add_state("online".to_string(), || async { "online".to_string() })
+        .add_state("charging".to_string(), || async { "full".to_string() })
+        .add_state("full".to_string(), || async { "full".to_string() })
+        .add_state("ready".to_string(), || async { "ready".to_string() })
+        .compound_state("connection", "offline", &["offline", "online"])
+        .compound_state("battery", "charging", &["charging", "full"])
+        .parallel_state("device", &["connection", "battery"])
+        .final_state("online")
+        .final_state("full")
+        .join("device", "ready")
+        .initial_state("device".to_string());
+        for state in ["device", "online", "full", "ready"] {
+            builder = builder
+                .on_enter(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("enter {state}"));
+                    async {}
+                })
+                .on_exit(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("exit {state}"));
+                    async {}
+                });
+        }
+        let mut machine = builder.build_step_machine();
+        futures::executor::block_on(machine.step());
+        assert_eq!(machine.get_context().regions, ["offline", "full"]);
+        machine.get_user_context_mut().dropped = false;
+        assert_eq!(
+            futures::executor::block_on(machine.step()),
+            TransitionOutcome::Transitioned(Transition {
+                from: "device".into(),
+                to: "ready".into()
+            })
+        );
+        assert!(machine.get_context().regions.is_empty());
+        assert_eq!(
+            machine.get_user_context().log,
+            [
+                "enter device",
+                "enter full",
+                "enter online",
+                "exit online",
+                "exit full",
+                "exit device",
+                "enter ready"
+            ]
+        );
+    }
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_outside_region_run_loop() {
+        use crate::error::Error;
+        use crate::event::MachineEvent;
+        use futures::StreamExt;
+        let client = crate::StateMachineBuilder::new(())
+            .add_state("offline".to_string(), || async { "full".to_string() })
+            .add_state("online".to_string(), || async { "online".to_string() })
+            .add_state("charging".to_string(), || async { "charging".to_string() })
+            .add_state("full".to_string(), || async { "full".to_string() })
+            .compound_state("connection", "offline", &["offline", "online"])
+            .compound_state("battery", "charging", &["charging", "full"])
+            .parallel_state("device", &["connection", "battery"])
+            .initial_state("device".to_string())
+            .build();
+        let mut events = client.subscribe();
+        client.run().await.unwrap();
+        // The run loop stops on the first tick instead of moving the region
+        let error = loop {
+            if let Some(MachineEvent::Failed(error)) = events.next().await {
+                break error;
+            }
+        };
+        assert_eq!(
+            error,
+            Error::OutsideRegion {
+                from: "offline".to_string(),
+                to: "full".to_string()
+            }
+        );
+        assert_eq!(client.get_context().await.regions, ["offline", "charging"]);
+        client.stop().await;
+    }
+    /// Parallel state "device" with regions "connection" and "battery", whose states
+    /// stay unless `targets` says otherwise and log leaving
+    fn device(targets: [(&'static str, &'static str); 2]) -> StateMachineBuilder<Link> {
+        let mut builder = StateMachineBuilder::new(Link::default())
+            .add_state("offline".to_string(), stay)
+            .add_state("online".to_string(), stay)
+            .add_state("charging".to_string(), stay)
+            .add_state("full".to_string(), stay)
+            .compound_state("connection", "offline", &["offline", "online"])
+            .compound_state("battery", "charging", &["charging", "full"])
+            .parallel_state("device", &["connection", "battery"])
+            .initial_state("device".to_string());
+        for (state, to) in targets {
+            builder = builder
+                .add_state(state.to_string(), move |_: StateMachineContext| {
+                    to.to_string()
+                })
+                .on_exit(state, move |_: &StateMachineContext, link: &mut Link| {
+                    link.log.push(format!("exit {state}"))
+                });
+        }
+        builder
+    }
+    #[test]
+    fn test_target_outside_region() {
+        // Another region's state, another region, and the parallel state itself
+        for (target, entered) in [
+            ("full", "full"),
+            ("battery", "charging"),
+            ("device", "device"),
+        ] {
+            let mut machine =
+                device([("offline", target), ("charging", "full")]).build_step_machine();
+            assert_eq!(
+                machine.step(),
+                TransitionOutcome::OutsideRegion(Transition {
+                    from: "offline".into(),
+                    to: entered.into()
+                })
+            );
+            let context = machine.get_context();
+            assert_eq!(context.current_state, "device");
+            assert_eq!(context.regions, ["offline", "charging"]);
+            assert!(machine.get_user_context().log.is_empty());
+        }
+    }
+    #[test]
+    fn test_later_region_refused() {
+        let mut machine =
+            device([("offline", "online"), ("charging", "connection")]).build_step_machine();
+        assert_eq!(
+            machine.step(),
+            TransitionOutcome::OutsideRegion(Transition {
+                from: "charging".into(),
+                to: "offline".into()
+            })
+        );
+        // The first region's move is dropped along with its hooks
+        assert_eq!(machine.get_context().regions, ["offline", "charging"]);
+        assert!(machine.get_user_context().log.is_empty());
+    }
+}
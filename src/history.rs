@@ -0,0 +1,149 @@
+//! Bounded history of the transitions a StateMachine made
+//!
+//! Every machine keeps its most recent transitions in a ring buffer, sized with the
+//! builder's `history` setting. The history travels with the context, so handlers can
+//! take it as an extractor and the handles return it with `get_history()`. Recording
+//! only copies the buffer while a handler still holds an older snapshot of it.
+//!
+//! ```rust
+//! use autostatemachine::{extractor::History, StateMachineBuilder};
+//! async fn connect(history: History) -> String {
+//!     // Bounced between connecting and backing off three times: give up
+//!     if history.count("backoff", "connect") >= 3 {
+//!         return "failed".to_string();
+//!     }
+//!     "backoff".to_string()
+//! }
+//! # async fn backoff() -> String { "connect".to_string() }
+//! # async fn failed() -> String { "failed".to_string() }
+//! let client = StateMachineBuilder::new(())
+//!     .add_state("connect".to_string(), connect)
+//!     .add_state("backoff".to_string(), backoff)
+//!     .add_state("failed".to_string(), failed)
+//!     .initial_state("connect".to_string())
+//!     .history(16)
+//!     .build();
+//! ```
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::context::StateMachineContext;
+use crate::event::TransitionEvent;
+use crate::extractor::FromContext;
+
+/// How many transitions a machine remembers unless told otherwise
+pub const DEFAULT_CAPACITY: usize = 32;
+
+/// The most recent transitions of a machine, oldest first
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct History {
+    entries: Arc<VecDeque<TransitionEvent>>,
+    capacity: usize,
+}
+
+impl History {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+    /// Remember `transition`, forgetting the oldest one if the history is full
+    pub(crate) fn record(&mut self, transition: TransitionEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entries = Arc::make_mut(&mut self.entries);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(transition);
+    }
+    /// How many transitions are remembered at most
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Iterate over the remembered transitions, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &TransitionEvent> + '_ {
+        self.entries.iter()
+    }
+    /// The most recent transition
+    pub fn last(&self) -> Option<&TransitionEvent> {
+        self.entries.back()
+    }
+    /// Up to `n` of the most recent transitions, oldest first
+    pub fn recent(&self, n: usize) -> impl Iterator<Item = &TransitionEvent> + '_ {
+        self.entries.iter().skip(self.len().saturating_sub(n))
+    }
+    /// How many of the remembered transitions went from `from` to `to`
+    pub fn count(&self, from: &str, to: &str) -> usize {
+        self.iter().filter(|t| t.from == from && t.to == to).count()
+    }
+}
+
+impl<S> FromContext<S> for History {
+    fn from_context(context: &StateMachineContext, _: &S) -> Self {
+        context.history.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn transition(from: &str, to: &str, tick: u64) -> TransitionEvent {
+        TransitionEvent {
+            from: from.into(),
+            to: to.into(),
+            tick,
+            at: SystemTime::now(),
+            duration_of_handler: Duration::ZERO,
+        }
+    }
+    #[test]
+    fn test_ring_buffer() {
+        let mut history = History::new(3);
+        for tick in 1..=4 {
+            history.record(transition("a", "b", tick));
+        }
+        let snapshot = history.clone();
+        history.record(transition("b", "a", 5));
+        assert_eq!(history.len(), 3);
+        assert_eq!(
+            history.iter().map(|t| t.tick).collect::<Vec<_>>(),
+            [3, 4, 5]
+        );
+        assert_eq!(history.recent(1).next().map(|t| t.tick), Some(5));
+        assert_eq!(history.count("a", "b"), 2);
+        // Snapshots are not affected by later transitions
+        assert_eq!(snapshot.last().map(|t| t.tick), Some(4));
+        let mut disabled = History::new(0);
+        disabled.record(transition("a", "b", 1));
+        assert!(disabled.is_empty());
+    }
+    #[test]
+    fn test_tick_records_in_place() {
+        let client = crate::blocking::StateMachineBuilder::new(())
+            .add_state("a".to_string(), |_: StateMachineContext| "b".to_string())
+            .add_state("b".to_string(), |_: StateMachineContext| "a".to_string())
+            .initial_state("a".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        client.run().unwrap();
+        client.wait_for_state("b", None).unwrap();
+        client.pause();
+        let entries = || Arc::as_ptr(&client.get_context().history.entries);
+        let before = entries();
+        // No snapshot outlives its tick, so the buffer is never copied
+        client.run_ticks(3).unwrap();
+        assert_eq!(entries(), before);
+        assert_eq!(client.get_context().history.len(), 4);
+    }
+}
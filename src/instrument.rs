@@ -0,0 +1,147 @@
+//! Spans and events of every StateMachine, through [`tracing`]
+//!
+//! With the `tracing` feature enabled, each machine gets a `state_machine` span with
+//! the name given to the builder in its `machine` field. Each handler call runs inside
+//! a child `handler` span with the `state` and the `tick` number, so whatever the
+//! handler logs is attributed to it. Transitions are logged at `DEBUG`, lifecycle
+//! changes at `INFO`, timed out waits at `WARN` and the errors that stop a machine at
+//! `ERROR`, all under the `autostatemachine` target.
+//!
+//! Machines built with `build_step_machine()` have no run loop and are not traced.
+use std::time::Duration;
+use tracing::{debug, error, info, info_span, warn, Span};
+
+use crate::error::Error;
+use crate::event::MachineEvent;
+use crate::state::StateName;
+
+const TARGET: &str = "autostatemachine";
+
+/// The span of the machine called `name`
+pub(crate) fn machine_span(name: &str) -> Span {
+    info_span!(target: TARGET, "state_machine", machine = name)
+}
+
+/// The span a handler for `state` runs in, on the `tick`th tick of the machine
+pub(crate) fn handler_span(machine: &Span, state: &StateName, tick: u64) -> Span {
+    info_span!(target: TARGET, parent: machine, "handler", state = %state, tick)
+}
+
+/// Log `event` in the span of its machine
+pub(crate) fn event(machine: &Span, event: &MachineEvent) {
+    match event {
+        MachineEvent::Transition(t) => debug!(
+            target: TARGET,
+            parent: machine,
+            from = %t.from,
+            to = %t.to,
+            tick = t.tick,
+            duration_of_handler = ?t.duration_of_handler,
+            "transition"
+        ),
+        MachineEvent::Started => info!(target: TARGET, parent: machine, "started"),
+        MachineEvent::Paused => info!(target: TARGET, parent: machine, "paused"),
+        MachineEvent::Resumed => info!(target: TARGET, parent: machine, "resumed"),
+        MachineEvent::Stopped => info!(target: TARGET, parent: machine, "stopped"),
+        MachineEvent::Failed(Error::UnknownState(state)) => error!(
+            target: TARGET,
+            parent: machine,
+            state = %state,
+            "transitioned to a state with no handler"
+        ),
+        MachineEvent::Failed(Error::HandlerPanicked(state)) => error!(
+            target: TARGET,
+            parent: machine,
+            state = %state,
+            "handler panicked"
+        ),
+        MachineEvent::Failed(error) => error!(target: TARGET, parent: machine, %error, "failed"),
+        MachineEvent::CheckpointFailed(error) => {
+            warn!(target: TARGET, parent: machine, %error, "checkpoint failed")
+        }
+        MachineEvent::External(external) => debug!(
+            target: TARGET,
+            parent: machine,
+            name = %external.name,
+            data = %external.data,
+            "external event"
+        ),
+        MachineEvent::Lagged(missed) => {
+            debug!(target: TARGET, parent: machine, missed, "subscriber lagged")
+        }
+    }
+}
+
+/// Log that waiting on the machine gave up after `timeout`
+pub(crate) fn timed_out(machine: &Span, timeout: Duration) {
+    warn!(target: TARGET, parent: machine, ?timeout, "wait timed out");
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use crate::{Error, StateMachineBuilder, StateMachineContext};
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    /// Collects everything the subscriber writes
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn test1(_: StateMachineContext) -> String {
+        tracing::info!("in test1");
+        "test2".to_string()
+    }
+    async fn test2(_: StateMachineContext) -> String {
+        "test1".to_string()
+    }
+    // The run loop has to run on this thread to see the subscriber
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_spans_and_events() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _default = tracing::subscriber::set_default(subscriber);
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .name("orders")
+            .build();
+        client.run().await.unwrap();
+        client.wait_for_state("test2", None).await.unwrap();
+        client.pause().await;
+        client.run_ticks(2).await.unwrap();
+        assert_eq!(
+            client
+                .wait_for_state("missing", Some(Duration::from_millis(1)))
+                .await
+                .err(),
+            Some(Error::Timeout)
+        );
+        client.stop().await;
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("state_machine{machine=\"orders\"}:handler{state=test1 tick=3}: "));
+        assert!(output.contains("in test1"));
+        assert!(output.contains("from=test1 to=test2 tick=3"));
+        assert!(output.contains("paused"));
+        assert!(output.contains("wait timed out"));
+        assert!(output.contains("stopped"));
+    }
+}
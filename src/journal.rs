@@ -0,0 +1,366 @@
+//! Append-only journal of a StateMachine, and replay of it
+//!
+//! A machine given a [`Journal`] with the builder's `journal()` appends one JSON line
+//! per [`MachineEvent`] it publishes: every transition, lifecycle change, external
+//! event recorded with `record_external()`, and error that stopped it. Each
+//! [`JournalEntry`] carries the time, the name of the machine and the state it was in.
+//! One journal can be shared by several machines. Entries are written in the order
+//! they were published, on a thread of the journal's own so the machine never waits
+//! for the disk; [`Journal::flush`] waits until they are all written, and
+//! [`Journal::flush_async`] does the same from async code.
+//!
+//! `replay()` on a [`StepMachine`](crate::StepMachine) built from the same builder
+//! feeds a journal back into it to reproduce what happened. Every recorded tick is
+//! stepped again and external events are handed to a callback that applies them to
+//! the user context, in the order they were recorded. Each run starts in the state the
+//! recorded run started in. The replay stops at the first tick whose outcome differs
+//! from the recorded one and reports it as a [`Divergence`].
+//!
+//! ```rust,no_run
+//! use autostatemachine::{journal::Journal, StateMachineBuilder, StateMachineContext};
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! async fn poll(_: StateMachineContext) -> String {
+//!     "poll".to_string()
+//! }
+//! let builder = || {
+//!     StateMachineBuilder::new(Vec::<String>::new())
+//!         .add_state("poll".to_string(), poll)
+//!         .initial_state("poll".to_string())
+//!         .name("orders")
+//! };
+//! let client = builder().journal(Journal::create("orders.jsonl")?).build();
+//! client.record_external("order", "{\"id\":1}").await;
+//!
+//! // Later, to reproduce an incident
+//! let journal = std::io::BufReader::new(std::fs::File::open("orders.jsonl")?);
+//! let report = builder()
+//!     .build_step_machine()
+//!     .replay(journal, |event, orders| orders.push(event.data.clone()))
+//!     .await?;
+//! if let Some(divergence) = report.divergence {
+//!     println!("diverged at line {}", divergence.line);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::SystemTime,
+};
+
+use crate::error::Error;
+use crate::event::{ExternalEvent, MachineEvent};
+use crate::runtime::{unblock, Executor, Worker};
+use crate::state::StateName;
+use crate::transition::TransitionOutcome;
+
+/// One line of a journal
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub at: SystemTime,
+    /// Name of the machine, as given to its builder
+    pub machine: String,
+    /// The state the machine was in once the event happened
+    pub state: StateName,
+    pub event: MachineEvent,
+}
+
+/// Where machines append their [`JournalEntry`]s. Clones write to the same place.
+#[derive(Clone)]
+pub struct Journal {
+    /// Only locked on the worker
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    worker: Arc<Worker>,
+    failed_writes: Arc<AtomicU64>,
+}
+
+impl Journal {
+    /// Append to the file at `path`, creating it if needed
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+    /// Append to `writer`. Every entry is written with a single `write_all` and flushed.
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            worker: Arc::default(),
+            failed_writes: Arc::default(),
+        }
+    }
+    /// How many entries could not be written and are missing from the journal
+    pub fn failed_writes(&self) -> u64 {
+        self.failed_writes.load(Ordering::Relaxed)
+    }
+    /// Block until every entry recorded so far has been written, and flush the writer.
+    /// This blocks the calling thread, so async code, such as a task on a runtime's
+    /// executor, should await [`Journal::flush_async`] instead.
+    pub fn flush(&self) -> io::Result<()> {
+        futures::executor::block_on(self.flush_async())
+    }
+    /// Wait until every entry recorded so far has been written, and flush the writer,
+    /// without blocking the calling thread
+    pub async fn flush_async(&self) -> io::Result<()> {
+        let writer = self.writer.clone();
+        unblock(&*self.worker, move || {
+            writer
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .flush()
+        })
+        .await
+    }
+    /// Queue `event` for the worker, which encodes and writes it. Called with the
+    /// machine locked, so it must not wait for the writer.
+    pub(crate) fn record(&self, machine: &str, state: &StateName, event: &MachineEvent) {
+        let entry = JournalEntry {
+            at: SystemTime::now(),
+            machine: machine.to_string(),
+            state: state.clone(),
+            event: event.clone(),
+        };
+        let (writer, failed_writes) = (self.writer.clone(), self.failed_writes.clone());
+        self.worker.spawn_blocking(Box::new(move || {
+            let written = serde_json::to_vec(&entry)
+                .map_err(io::Error::from)
+                .and_then(|mut line| {
+                    line.push(b'\n');
+                    let mut writer = writer.lock().unwrap_or_else(PoisonError::into_inner);
+                    writer.write_all(&line)?;
+                    writer.flush()
+                });
+            if written.is_err() {
+                failed_writes.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+    }
+}
+
+/// The first recorded tick a replay did not reproduce
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// Line of the entry in the journal, from 1
+    pub line: usize,
+    pub recorded: JournalEntry,
+    /// What the replayed handler did instead, or `None` if it panicked
+    pub replayed: Option<TransitionOutcome>,
+}
+
+/// How a replay went
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Ticks stepped, including the one that diverged
+    pub ticks: u64,
+    /// `None` if every recorded tick was reproduced
+    pub divergence: Option<Divergence>,
+}
+
+/// What a replay does with an entry
+pub(crate) enum Replay<'a> {
+    /// Step and compare the outcome with the recorded one
+    Tick,
+    /// Hand the event to the callback
+    Feed(&'a ExternalEvent),
+    /// A new run started, in the recorded state
+    Restart,
+    Skip,
+}
+
+impl<'a> Replay<'a> {
+    pub(crate) fn of(event: &'a MachineEvent) -> Self {
+        match event {
+            MachineEvent::Transition(_)
+            | MachineEvent::Failed(
+                Error::UnknownState(_) | Error::HandlerPanicked(_) | Error::OutsideRegion { .. },
+            ) => Replay::Tick,
+            MachineEvent::External(external) => Replay::Feed(external),
+            MachineEvent::Started => Replay::Restart,
+            _ => Replay::Skip,
+        }
+    }
+}
+
+/// Whether a replayed tick did what was `recorded`. `replayed` is `None` if the
+/// handler panicked.
+pub(crate) fn reproduces(recorded: &MachineEvent, replayed: Option<&TransitionOutcome>) -> bool {
+    match (recorded, replayed) {
+        (MachineEvent::Transition(t), Some(TransitionOutcome::Transitioned(transition))) => {
+            t.from == transition.from && t.to == transition.to
+        }
+        (
+            MachineEvent::Failed(Error::UnknownState(recorded)),
+            Some(TransitionOutcome::UnknownState(state)),
+        ) => recorded == state.as_str(),
+        (
+            MachineEvent::Failed(Error::OutsideRegion { from, to }),
+            Some(TransitionOutcome::OutsideRegion(transition)),
+        ) => transition.from == from.as_str() && transition.to == to.as_str(),
+        (MachineEvent::Failed(Error::HandlerPanicked(_)), None) => true,
+        _ => false,
+    }
+}
+
+/// The entries of `machine` in `journal`, with their line numbers
+pub(crate) fn entries<'a>(
+    journal: impl BufRead + 'a,
+    machine: &'a str,
+) -> impl Iterator<Item = Result<(usize, JournalEntry), Error>> + 'a {
+    journal.lines().enumerate().filter_map(move |(i, line)| {
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => return None,
+            Ok(line) => line,
+            Err(e) => return Some(Err(Error::Journal(e.to_string()))),
+        };
+        match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) if entry.machine != machine => None,
+            Ok(entry) => Some(Ok((i + 1, entry))),
+            Err(e) => Some(Err(Error::Journal(format!("line {}: {e}", i + 1)))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::{StateMachineBuilder, StateMachineContext};
+    use crate::event::TransitionEvent;
+    use crate::transition::Transition;
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let output = Output::default();
+        let journal = Journal::new(output.clone());
+        let transition = MachineEvent::Transition(TransitionEvent {
+            from: "a".into(),
+            to: "b".into(),
+            tick: 1,
+            at: SystemTime::now(),
+            duration_of_handler: Duration::from_millis(1),
+        });
+        journal.record("orders", &"b".into(), &transition);
+        journal.record("other", &"b".into(), &MachineEvent::Started);
+        let failed = MachineEvent::Failed(Error::UnknownState("c".to_string()));
+        journal.record("orders", &"c".into(), &failed);
+        journal.flush().unwrap();
+        let output = output.0.lock().unwrap().clone();
+        assert_eq!(output.iter().filter(|&&b| b == b'\n').count(), 3);
+        let recorded: Vec<_> = entries(&output[..], "orders")
+            .map(Result::unwrap)
+            .map(|(line, entry)| (line, entry.event))
+            .collect();
+        assert_eq!(recorded, [(1, transition), (3, failed)]);
+        assert!(matches!(
+            entries(&b"{}\n"[..], "orders").next(),
+            Some(Err(Error::Journal(_)))
+        ));
+    }
+    fn idle(_: &StateMachineContext, orders: &mut Vec<String>) -> String {
+        match orders.pop() {
+            Some(_) => "busy".to_string(),
+            None => "idle".to_string(),
+        }
+    }
+    fn busy(_: &StateMachineContext, _: &mut Vec<String>) -> String {
+        "idle".to_string()
+    }
+    fn builder() -> StateMachineBuilder<Vec<String>> {
+        StateMachineBuilder::new(Vec::new())
+            .add_state("idle".to_string(), idle)
+            .add_state("busy".to_string(), busy)
+            .initial_state("idle".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .name("orders")
+    }
+    #[test]
+    fn test_replay() {
+        let output = Output::default();
+        let journal = Journal::new(output.clone());
+        let client = builder().journal(journal.clone()).build();
+        client.run().unwrap();
+        client.pause();
+        client.get_user_context().push("1".to_string());
+        client.record_external("order", "1");
+        client.run_ticks(3).unwrap();
+        client.stop();
+        journal.flush().unwrap();
+        let output = output.0.lock().unwrap().clone();
+        let recorded = client.get_history().len() as u64;
+
+        let feed = |event: &ExternalEvent, orders: &mut Vec<String>| {
+            orders.push(event.data.clone());
+        };
+        let report = builder()
+            .build_step_machine()
+            .replay(&output[..], feed)
+            .unwrap();
+        assert_eq!(report.ticks, recorded);
+        assert_eq!(report.divergence, None);
+
+        // Without the order the machine never gets busy
+        let report = builder()
+            .build_step_machine()
+            .replay(&output[..], |_, _| {})
+            .unwrap();
+        let divergence = report.divergence.unwrap();
+        assert!(matches!(
+            divergence.recorded.event,
+            MachineEvent::Transition(TransitionEvent { ref to, .. }) if to == "busy"
+        ));
+        assert_eq!(
+            divergence.replayed,
+            Some(TransitionOutcome::Transitioned(Transition {
+                from: "idle".into(),
+                to: "idle".into()
+            }))
+        );
+    }
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_flush_async() {
+        let output = Output::default();
+        let journal = Journal::new(output.clone());
+        let client = crate::StateMachineBuilder::new(())
+            .add_state("idle".to_string(), || async { "idle".to_string() })
+            .initial_state("idle".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .name("orders")
+            .journal(journal.clone())
+            .build();
+        client.run().await.unwrap();
+        client
+            .wait_until(|context| context.history.len() == 1, None)
+            .await
+            .unwrap();
+        client.stop().await;
+        // Awaited on the runtime's only thread
+        journal.flush_async().await.unwrap();
+        let output = output.0.lock().unwrap().clone();
+        let events: Vec<_> = entries(&output[..], "orders")
+            .map(|entry| entry.unwrap().1.event)
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], MachineEvent::Started);
+        assert_eq!(events[2], MachineEvent::Stopped);
+    }
+}
@@ -13,6 +13,10 @@
 //!   fine-tuned control over execution speed.
 //! - **User Context**: Pass a user-defined context through states, enabling stateful operations and data
 //!   persistence across state transitions.
+//! - **Any Runtime**: Runs on tokio by default; enable the `smol` or `async-std` feature, or
+//!   bring your own executor, with [`StateMachineBuilder::runtime`]. See [`runtime`].
+//! - **Local Machines**: Use `Rc`-based contexts and `!Send` handlers on a tokio `LocalSet`
+//!   with [`StateMachineBuilder::new_local`]. See [`flavor`].
 //!
 //! ## Quick Start
 //!
@@ -36,13 +40,13 @@
 //!     "init".to_string()
 //! }
 //!
-//! let mut client = StateMachineBuilder::new(())
+//! let client = StateMachineBuilder::new(())
 //!     .add_state("init".to_string(), sample_callback)
 //!     .initial_state("init".to_string())
 //!     .tick_rate(Duration::from_secs(1))
 //!     .build();
 //!
-//! client.run().await;
+//! client.run().await.unwrap();
 //! // The client is now running, transitioning from "init" to "next_state"
 //! // according to the logic you've defined.
 //! tokio::time::sleep(Duration::from_millis(50));
@@ -52,38 +56,99 @@
 //!
 //! ## Control Flow Methods
 //!
-//! - `run()`: Start the client's execution, allowing state transitions to occur.
+//! - `run()`: Start the client's execution, allowing state transitions to occur. Only one run
+//!   loop may be active at a time.
 //! - `pause()`: Pause the execution, freezing the current state.
 //! - `resume()`: Resume execution from the current state.
-//! - `stop()`: Stop execution, resetting to the initial state.
+//! - `step()`, `run_ticks(n)`, `run_until(predicate)`: Advance a paused client tick by tick.
+//! - `stop()`: Stop execution and wait for the loop to exit, resetting to the initial state
+//!   (or preserving the current one with `stop_with(StopMode::Preserve)`).
+//! - `handle()`: Get a cloneable `StateMachineHandle` exposing all of the above by `&self`.
+//! - `blocking_handle()`: Get a `BlockingHandle` exposing the same methods to synchronous code.
 //!
 //! This crate aims to simplify the creation of automated, state-driven systems with minimal boilerplate
 //! and high flexibility. For more detailed documentation and advanced usage, please refer to the specific
 //! module and method documentation within the crate.
 pub mod blocking;
+mod blocking_handle;
 mod builder;
 mod callback;
+#[cfg(feature = "checkpoint")]
+pub mod checkpoint;
 pub mod context;
+pub mod error;
+pub mod event;
 pub mod extractor;
+pub mod flavor;
+mod handle;
+pub mod hierarchy;
+pub mod history;
+#[cfg(feature = "tracing")]
+mod instrument;
+#[cfg(feature = "journal")]
+pub mod journal;
+mod machine;
+pub mod runtime;
+#[cfg(feature = "serde")]
+pub mod snapshot;
+pub mod state;
+pub mod stats;
+mod step;
+#[cfg(feature = "metrics")]
+pub mod telemetry;
+pub mod transition;
+pub use blocking_handle::BlockingHandle;
 pub use builder::StateMachineBuilder;
-pub use context::StateMachineContext;
-use std::{collections::HashMap, sync::Arc, time::Duration};
-use tokio::sync::Mutex;
+pub use context::{StateMachineContext, StopMode, UserContext};
+pub use error::Error;
+pub use event::{ExternalEvent, MachineEvent, TransitionEvent};
+pub use flavor::{Local, Threaded};
+pub use handle::StateMachineHandle;
+#[cfg(feature = "serde")]
+pub use snapshot::MachineSnapshot;
+pub use state::{StateId, StateName, Target};
+pub use stats::MachineStats;
+use std::{collections::HashMap, time::Duration};
+pub use step::StepMachine;
+pub use transition::{Transition, TransitionOutcome};
+
+use handle::MachineConfig;
 
 use callback::StoredCallback;
+use flavor::Flavor;
+
+/// A state machine whose handlers and user context do not have to be `Send`, run with
+/// `spawn_local`. See the [`flavor`] module.
+pub type LocalStateMachine<S> = StateMachine<S, Local>;
+/// Cloneable handle of a [`LocalStateMachine`]. Not `Send`.
+pub type LocalStateMachineHandle<S> = StateMachineHandle<S, Local>;
 
-pub struct StateMachine<S>
+/// A state machine and the owner of its run loop.
+///
+/// Dropping the machine tells its run loop to stop (using the configured [`StopMode`])
+/// without waiting for it. Use [`detach`](Self::detach) to keep the loop running.
+pub struct StateMachine<S, F = Threaded>
 where
-    S: Clone + Send + Sync + 'static,
+    S: 'static,
+    F: Flavor<S>,
 {
-    handlers: Arc<HashMap<String, StoredCallback<S>>>,
-    tick_rate: Duration,
-    context: Arc<Mutex<StateMachineContext>>,
-    user_context: S,
+    handle: StateMachineHandle<S, F>,
+    detached: bool,
+}
+impl<S, F> Drop for StateMachine<S, F>
+where
+    S: 'static,
+    F: Flavor<S>,
+{
+    fn drop(&mut self) {
+        if !self.detached {
+            self.handle.request_stop(self.handle.shared.stop_mode);
+        }
+    }
 }
 impl<S> StateMachine<S>
 where
-    S: Clone + Send + Sync,
+    S: Send + Sync + 'static,
 {
     pub fn new(
         handlers: HashMap<String, StoredCallback<S>>,
@@ -91,78 +156,159 @@ where
         initial_state: String,
         user_context: S,
     ) -> Self {
-        Self {
-            handlers: Arc::new(handlers),
-            tick_rate,
-            context: Arc::new(Mutex::new(StateMachineContext {
+        Self::from_config(
+            MachineConfig::<S>::new(
+                state::States::new(handlers),
+                Vec::new(),
                 tick_rate,
-                current_state: initial_state.clone(),
-                initial_state,
-                life_cycle: context::LifeCycle::Stopped,
-            })),
-            user_context,
+                &initial_state,
+                StopMode::default(),
+                user_context,
+            ),
+            runtime::Runtime::default_for_features(),
+        )
+    }
+    /// Start the run loop. See [`StateMachineHandle::run`].
+    pub async fn run(&self) -> Result<(), Error> {
+        self.handle.run().await
+    }
+    /// Get a handle that controls this machine from synchronous code. See
+    /// [`BlockingHandle`].
+    pub fn blocking_handle(&self) -> BlockingHandle<S> {
+        self.handle.blocking_handle()
+    }
+}
+impl<S> StateMachine<S, Local>
+where
+    S: 'static,
+{
+    /// Start the run loop with `spawn_local`. See [`LocalStateMachineHandle::run`].
+    pub async fn run(&self) -> Result<(), Error> {
+        self.handle.run().await
+    }
+}
+impl<S, F> StateMachine<S, F>
+where
+    S: 'static,
+    F: Flavor<S>,
+{
+    pub(crate) fn from_config(
+        config: MachineConfig<S, F>,
+        runtime: Option<runtime::Runtime<F::Executor>>,
+    ) -> Self {
+        Self {
+            handle: StateMachineHandle::new(config, runtime),
+            detached: false,
         }
     }
+    /// Give up ownership of the run loop, so it keeps running after the machine is
+    /// gone. The returned handle is then the only way to stop it.
+    pub fn detach(mut self) -> StateMachineHandle<S, F> {
+        self.detached = true;
+        self.handle.clone()
+    }
+    /// Get a cloneable handle that can control this machine from other tasks
+    pub fn handle(&self) -> StateMachineHandle<S, F> {
+        self.handle.clone()
+    }
     pub async fn get_context(&self) -> StateMachineContext {
-        self.context.lock().await.clone()
+        self.handle.get_context().await
+    }
+    pub async fn get_history(&self) -> history::History {
+        self.handle.get_history().await
+    }
+    pub async fn get_stats(&self) -> MachineStats {
+        self.handle.get_stats().await
+    }
+    /// See [`StateMachineHandle::record_external`]
+    pub async fn record_external(&self, name: &str, data: &str) {
+        self.handle.record_external(name, data).await
+    }
+    /// See [`StateMachineHandle::snapshot`]
+    #[cfg(feature = "serde")]
+    pub async fn snapshot(&self) -> MachineSnapshot<S> {
+        self.handle.snapshot().await
     }
-    pub fn get_user_context(&self) -> &S {
-        &self.user_context
+    /// See [`StateMachineHandle::snapshot_with_context`]
+    #[cfg(feature = "serde")]
+    pub async fn snapshot_with_context(&self) -> MachineSnapshot<S>
+    where
+        S: Clone,
+    {
+        self.handle.snapshot_with_context().await
+    }
+    pub async fn get_user_context(&self) -> UserContext<'_, S> {
+        self.handle.get_user_context().await
     }
     pub fn get_tick_rate(&self) -> &Duration {
-        &self.tick_rate
+        self.handle.get_tick_rate()
     }
-    pub async fn pause(&mut self) {
-        self.context.lock().await.life_cycle = context::LifeCycle::Paused;
+    /// Stream every [`MachineEvent`] from now on. See [`StateMachineHandle::subscribe`].
+    pub fn subscribe(&self) -> futures::channel::mpsc::Receiver<MachineEvent> {
+        self.handle.subscribe()
     }
-    pub async fn resume(&mut self) {
-        self.context.lock().await.life_cycle = context::LifeCycle::Running;
+    pub fn subscribe_with_capacity(
+        &self,
+        capacity: usize,
+    ) -> futures::channel::mpsc::Receiver<MachineEvent> {
+        self.handle.subscribe_with_capacity(capacity)
     }
-    pub async fn stop(&mut self) {
-        self.context.lock().await.life_cycle = context::LifeCycle::Stopped;
+    pub fn is_running(&self) -> bool {
+        self.handle.is_running()
     }
-    pub async fn run(&mut self) {
-        {
-            self.context.lock().await.life_cycle = context::LifeCycle::Running;
-        }
-        let context = self.context.clone();
-        let user_context = self.user_context.clone();
-        let handlers = self.handlers.clone();
-        tokio::spawn(async move {
-            loop {
-                let mut context_guard = context.lock().await;
-                let tick_rate = context_guard.tick_rate;
-                match context_guard.life_cycle {
-                    context::LifeCycle::Paused => {
-                        drop(context_guard);
-                        tokio::time::sleep(tick_rate).await;
-                    }
-                    context::LifeCycle::Stopped => {
-                        context_guard.life_cycle = context::LifeCycle::Stopped;
-                        context_guard.current_state = context_guard.initial_state.clone();
-                        break;
-                    }
-                    context::LifeCycle::Running => {
-                        let handler = handlers.get(&context_guard.current_state).unwrap();
-                        let output = handler
-                            .call(&context_guard, &mut user_context.clone())
-                            .await;
-                        context_guard.current_state = output;
-                        drop(context_guard);
-                        tokio::time::sleep(tick_rate).await;
-                    }
-                }
-            }
-        });
+    pub async fn pause(&self) {
+        self.handle.pause().await
+    }
+    pub async fn resume(&self) {
+        self.handle.resume().await
+    }
+    pub async fn step(&self) -> Result<Transition, Error> {
+        self.handle.step().await
+    }
+    pub async fn run_ticks(&self, n: usize) -> Result<Vec<Transition>, Error> {
+        self.handle.run_ticks(n).await
+    }
+    pub async fn run_until<P>(&self, predicate: P) -> Result<Vec<Transition>, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        self.handle.run_until(predicate).await
+    }
+    pub async fn wait_for_state(
+        &self,
+        state: &str,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error> {
+        self.handle.wait_for_state(state, timeout).await
+    }
+    pub async fn wait_until<P>(
+        &self,
+        predicate: P,
+        timeout: Option<Duration>,
+    ) -> Result<StateMachineContext, Error>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        self.handle.wait_until(predicate, timeout).await
+    }
+    pub async fn stop(&self) {
+        self.handle.stop().await
+    }
+    pub async fn stop_with(&self, mode: StopMode) {
+        self.handle.stop_with(mode).await
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "tokio"))]
 mod tests {
-    use tokio::time::sleep;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
 
     use super::*;
     use crate::builder::StateMachineBuilder;
+    use crate::extractor::State;
 
     async fn test1(_: StateMachineContext) -> String {
         println!("test1");
@@ -172,36 +318,471 @@ mod tests {
         println!("test2");
         "test1".to_string()
     }
-    #[tokio::test]
-    async fn test_basic_run() {
-        let mut client = StateMachineBuilder::new("".to_string())
+    /// A machine bouncing between `test1` and `test2`, starting in `test1`
+    fn ping_pong<S: Send + Sync + 'static>(user_context: S) -> StateMachineBuilder<S> {
+        StateMachineBuilder::new(user_context)
             .add_state("test1".to_string(), test1)
             .add_state("test2".to_string(), test2)
             .initial_state("test1".to_string())
+    }
+    /// Start `client` and wait for its first transition, into `test2`
+    async fn run_to_test2<S: Send + Sync + 'static>(client: &StateMachine<S>) {
+        client.run().await.unwrap();
+        client
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+    }
+    #[tokio::test]
+    async fn test_basic_run() {
+        let client = ping_pong("".to_string()).build();
+        client.run().await.unwrap();
+        let context = client
+            .wait_until(
+                |context| context.history.len() == 2,
+                Some(Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(context.current_state, "test1");
+        assert_eq!(
+            context
+                .history
+                .iter()
+                .map(|t| t.to.as_str())
+                .collect::<Vec<_>>(),
+            ["test2", "test1"]
+        );
+        client.stop().await;
+    }
+    #[tokio::test]
+    async fn test_pause() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_millis(1))
             .build();
-        client.run().await;
-        sleep(Duration::from_millis(10)).await;
-        assert_eq!(client.get_context().await.current_state, "test2");
-        sleep(Duration::from_millis(51)).await;
+        client.run().await.unwrap();
+        client.wait_for_state("test2", None).await.unwrap();
+        client.pause().await;
+        // Once the tick in progress is done, no other starts while paused
+        drop(client.get_user_context().await);
+        let ticks = client.get_stats().await.ticks;
+        let paused_in = client.get_context().await.current_state;
+        assert_eq!(client.step().await.unwrap().from, paused_in);
+        assert_eq!(client.get_stats().await.ticks, ticks + 1);
+        client.resume().await;
+        client
+            .wait_until(
+                |context| context.history.last().is_some_and(|t| t.tick > ticks + 1),
+                None,
+            )
+            .await
+            .unwrap();
+        client.stop().await;
+    }
+    #[tokio::test]
+    async fn test_handle_controls_machine() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        let handle = client.handle();
+        let remote = handle.clone();
+        tokio::spawn(async move { remote.run().await })
+            .await
+            .unwrap()
+            .unwrap();
+        handle
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        let remote = handle.clone();
+        tokio::spawn(async move { remote.pause().await })
+            .await
+            .unwrap();
+        // Only a paused machine can be stepped
+        assert_eq!(client.step().await.unwrap().to, "test1");
+        handle.stop().await;
+    }
+    #[test]
+    fn test_handle_is_send_sync() {
+        fn assert_send_sync<T: Clone + Send + Sync>() {}
+        assert_send_sync::<StateMachineHandle<String>>();
+    }
+    #[tokio::test]
+    async fn test_single_run_loop() {
+        let client = ping_pong("".to_string()).build();
+        client.run().await.unwrap();
+        assert_eq!(client.run().await, Err(Error::AlreadyRunning));
+        client.pause().await;
+        assert_eq!(client.run().await, Err(Error::AlreadyRunning));
+        client.stop().await;
+        // Waits for the stopped loop to exit before starting a fresh one
+        client.run().await.unwrap();
+        assert!(client.is_running());
         assert_eq!(client.get_context().await.current_state, "test1");
         client.stop().await;
     }
     #[tokio::test]
-    async fn test_pause() {
-        let mut client = StateMachineBuilder::new("".to_string())
-            .add_state("test1".to_string(), test1)
-            .add_state("test2".to_string(), test2)
-            .initial_state("test1".to_string())
+    async fn test_stop_waits_for_hooks_and_resets() {
+        let stops = Arc::new(AtomicUsize::new(0));
+        let client = ping_pong(stops.clone())
+            .on_stop(|State(stops): State<Arc<AtomicUsize>>| async move {
+                stops.fetch_add(1, Ordering::SeqCst);
+            })
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        run_to_test2(&client).await;
+        client.stop().await;
+        assert!(!client.is_running());
+        assert_eq!(stops.load(Ordering::SeqCst), 1);
+        assert_eq!(client.get_context().await.current_state, "test1");
+    }
+    #[tokio::test]
+    async fn test_stop_preserve() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
             .build();
-        client.run().await;
-        sleep(Duration::from_millis(10)).await;
+        run_to_test2(&client).await;
+        client.stop_with(StopMode::Preserve).await;
         assert_eq!(client.get_context().await.current_state, "test2");
+        // Picks up where it left off
+        client.run().await.unwrap();
+        let context = client
+            .wait_until(
+                |context| context.history.len() == 2,
+                Some(Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(context.history.last().unwrap().from, "test2");
+        client.stop().await;
+        assert_eq!(client.get_context().await.current_state, "test1");
+    }
+    #[tokio::test]
+    async fn test_drop_stops_loop() {
+        use futures::StreamExt;
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        run_to_test2(&client).await;
+        let handle = client.handle();
+        let events = handle.subscribe();
+        drop(client);
+        // The loop exits without anyone calling stop
+        let mut stopped =
+            events.skip_while(|event| futures::future::ready(*event != MachineEvent::Stopped));
+        tokio::time::timeout(Duration::from_secs(1), stopped.next())
+            .await
+            .unwrap();
+        assert_eq!(handle.get_context().await.current_state, "test1");
+    }
+    #[tokio::test]
+    async fn test_detach_keeps_loop() {
+        let client = ping_pong("".to_string()).build();
+        client.run().await.unwrap();
+        let handle = client.detach();
+        // Still ticking after the machine is gone
+        handle
+            .wait_until(
+                |context| context.history.last().is_some_and(|t| t.tick >= 3),
+                Some(Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        assert!(handle.is_running());
+        handle.stop().await;
+        assert!(!handle.is_running());
+    }
+    #[tokio::test]
+    async fn test_control_is_immediate() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(3600))
+            .build();
+        client.run().await.unwrap();
+        // The first tick runs at once, then the loop sleeps for an hour
+        client.wait_for_state("test2", None).await.unwrap();
+        client.pause().await;
+        client.step().await.unwrap();
+        client.resume().await;
+        // Stopping interrupts the sleep, or this would not return for an hour
+        client.stop().await;
+        assert_eq!(client.get_stats().await.ticks, 2);
+    }
+    #[tokio::test]
+    async fn test_step_while_paused() {
+        let client = ping_pong("".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        assert_eq!(client.step().await, Err(Error::NotPaused));
+        client.run().await.unwrap();
+        assert_eq!(client.step().await, Err(Error::NotPaused));
+        client
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
         client.pause().await;
-        sleep(Duration::from_millis(51)).await;
         assert_eq!(client.get_context().await.current_state, "test2");
+        let transition = client.step().await.unwrap();
+        assert_eq!(transition.from, "test2");
+        assert_eq!(transition.to, "test1");
+        assert_eq!(client.run_ticks(3).await.unwrap().len(), 3);
+        assert_eq!(client.get_context().await.current_state, "test2");
+        let transitions = client
+            .run_until(|ctx| ctx.current_state == "test1")
+            .await
+            .unwrap();
+        assert_eq!(
+            transitions,
+            vec![Transition {
+                from: "test2".into(),
+                to: "test1".into()
+            }]
+        );
+        client.stop().await;
+    }
+    #[tokio::test]
+    async fn test_unknown_state_stops_loop() {
+        let client = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), || async { "missing".to_string() })
+            .initial_state("test1".to_string())
+            .stop_mode(StopMode::Preserve)
+            .build();
+        client.run().await.unwrap();
+        let context = client
+            .wait_until(
+                |context| matches!(context.life_cycle, context::LifeCycle::Stopped),
+                Some(Duration::from_secs(1)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(context.current_state, "missing");
+    }
+    #[tokio::test]
+    async fn test_history() {
+        let client = ping_pong(())
+            .tick_rate(Duration::from_secs(10))
+            .history(2)
+            .build();
+        assert!(client.get_history().await.is_empty());
+        run_to_test2(&client).await;
+        client.pause().await;
+        client.run_ticks(2).await.unwrap();
+        let history = client.get_history().await;
+        assert_eq!(history.capacity(), 2);
+        assert_eq!(history.iter().map(|t| t.tick).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(history.count("test2", "test1"), 1);
+        assert_eq!(history.last().map(|t| t.to.as_str()), Some("test2"));
+        client.stop().await;
+    }
+    #[tokio::test]
+    async fn test_stats() {
+        let client = ping_pong(()).tick_rate(Duration::from_secs(10)).build();
+        run_to_test2(&client).await;
+        client.pause().await;
+        client.run_ticks(3).await.unwrap();
+        client.stop().await;
+        let stats = client.get_stats().await;
+        assert_eq!(stats.ticks, 4);
+        assert_eq!(stats.transitions("test1", "test2"), 2);
+        assert_eq!(stats.transitions("test2", "test1"), 2);
+        let test1 = stats.state("test1").unwrap();
+        assert_eq!((test1.visits, test1.ticks), (3, 2));
+        assert_eq!(test1.handler_latency.count(), 2);
+        // Stopping ended the last visit
+        assert_eq!(stats, client.get_stats().await);
+    }
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        let client = ping_pong("before".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .stop_mode(StopMode::Preserve)
+            .build();
+        run_to_test2(&client).await;
+        client.pause().await;
+        client.run_ticks(2).await.unwrap();
+        *client.get_user_context().await = "after".to_string();
+        let json = serde_json::to_string(&client.snapshot_with_context().await).unwrap();
+        client.stop().await;
+        let snapshot: MachineSnapshot<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.current_state, "test2");
+        assert_eq!(snapshot.life_cycle, context::LifeCycle::Paused);
+        assert_eq!(snapshot.ticks, 3);
+        let restored = ping_pong("before".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .restore(snapshot)
+            .build();
+        let context = restored.get_context().await;
+        assert_eq!(context.current_state, "test2");
+        assert_eq!(context.history.len(), 3);
+        assert_eq!(*restored.get_user_context().await, "after");
+        // Picks up at the restored state, and tick numbers carry on
+        restored.run().await.unwrap();
+        restored
+            .wait_for_state("test1", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        restored.pause().await;
+        let last = restored.get_history().await.last().cloned().unwrap();
+        assert_eq!((last.from.as_str(), last.tick), ("test2", 4));
+        assert_eq!(restored.snapshot().await.user_context, None);
+        restored.stop().await;
+    }
+    #[tokio::test]
+    async fn test_wait_for_state() {
+        let client = ping_pong(()).tick_rate(Duration::from_secs(10)).build();
+        // Already holds
+        let context = client.wait_for_state("test1", None).await.unwrap();
+        assert_eq!(context.current_state, "test1");
+        client.run().await.unwrap();
+        let context = client
+            .wait_for_state("test2", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert_eq!(context.current_state, "test2");
+        assert_eq!(
+            client
+                .wait_for_state("test1", Some(Duration::from_millis(20)))
+                .await
+                .err(),
+            Some(Error::Timeout)
+        );
+        client.stop().await;
+    }
+    #[tokio::test]
+    async fn test_subscribe() {
+        use futures::StreamExt;
+        let client = ping_pong(()).tick_rate(Duration::from_secs(10)).build();
+        let mut events = client.subscribe();
+        run_to_test2(&client).await;
+        client.pause().await;
+        client.pause().await;
+        client.step().await.unwrap();
+        client.stop().await;
+        assert_eq!(events.next().await, Some(MachineEvent::Started));
+        match events.next().await {
+            Some(MachineEvent::Transition(t)) => {
+                assert_eq!((t.from, t.to, t.tick), ("test1".into(), "test2".into(), 1));
+            }
+            other => panic!("expected a transition, got {other:?}"),
+        }
+        // Pausing twice changes nothing the second time
+        assert_eq!(events.next().await, Some(MachineEvent::Paused));
+        assert!(matches!(
+            events.next().await,
+            Some(MachineEvent::Transition(TransitionEvent { tick: 2, .. }))
+        ));
+        assert_eq!(events.next().await, Some(MachineEvent::Stopped));
+        // Lagging subscribers hear how much they missed
+        let mut lagging = client.subscribe_with_capacity(1);
+        client.run().await.unwrap();
+        client.pause().await;
         client.resume().await;
-        sleep(Duration::from_millis(51)).await;
-        assert_eq!(client.get_context().await.current_state, "test1");
+        client.stop().await;
+        assert_eq!(lagging.next().await, Some(MachineEvent::Started));
+        // Reported once there is room again
+        client.run().await.unwrap();
+        assert!(matches!(
+            lagging.next().await,
+            Some(MachineEvent::Lagged(missed)) if missed >= 3
+        ));
+        client.stop().await;
+    }
+    #[tokio::test]
+    async fn test_local_machine() {
+        use std::{cell::Cell, rc::Rc};
+        async fn count(State(count): State<Rc<Cell<usize>>>) -> String {
+            // Held across an await, so the future is not Send
+            let held = count.clone();
+            tokio::task::yield_now().await;
+            held.set(held.get() + 1);
+            "bump".to_string()
+        }
+        async fn bump(_: &StateMachineContext, count: &mut Rc<Cell<usize>>) -> String {
+            tokio::task::yield_now().await;
+            count.set(count.get() + 1);
+            "count".to_string()
+        }
+        let count_ref = Rc::new(Cell::new(0));
+        let client: LocalStateMachine<_> = StateMachineBuilder::new_local(count_ref.clone())
+            .add_state("count".to_string(), count)
+            .add_state("bump".to_string(), bump)
+            .on_stop(|State(count): State<Rc<Cell<usize>>>| async move {
+                count.set(count.get() + 100);
+            })
+            .initial_state("count".to_string())
+            .build();
+        tokio::task::LocalSet::new()
+            .run_until(async {
+                client.run().await.unwrap();
+                assert_eq!(client.run().await, Err(Error::AlreadyRunning));
+                client
+                    .wait_until(|context| context.history.len() == 1, None)
+                    .await
+                    .unwrap();
+                client.pause().await;
+                client.step().await.unwrap();
+                client.stop().await;
+            })
+            .await;
+        assert_eq!(count_ref.get(), 102);
+    }
+    #[tokio::test]
+    async fn test_borrowing_handler() {
+        // Not Clone: handlers get the machine's own copy
+        struct Counter {
+            ticks: usize,
+        }
+        async fn count(context: &StateMachineContext, counter: &mut Counter) -> String {
+            tokio::task::yield_now().await;
+            counter.ticks += 1;
+            context.current_state.to_string()
+        }
+        let client = StateMachineBuilder::new(Counter { ticks: 0 })
+            .add_state("count".to_string(), count)
+            .initial_state("count".to_string())
+            .build();
+        client.run().await.unwrap();
+        client
+            .wait_until(|context| context.history.len() == 1, None)
+            .await
+            .unwrap();
+        client.pause().await;
+        client.run_ticks(2).await.unwrap();
+        client.stop().await;
+        assert_eq!(client.get_user_context().await.ticks, 3);
+    }
+    #[tokio::test]
+    async fn test_blocking_state() {
+        use futures::{channel::mpsc, StreamExt};
+        use std::sync::Mutex;
+        type Gate = (
+            mpsc::UnboundedSender<()>,
+            Arc<Mutex<std::sync::mpsc::Receiver<()>>>,
+        );
+        fn crunch(_: StateMachineContext, State((entered, release)): State<Gate>) -> String {
+            entered.unbounded_send(()).unwrap();
+            // Only released by the test, which needs the runtime thread to get here
+            let released = release.lock().unwrap().recv_timeout(Duration::from_secs(5));
+            assert!(released.is_ok(), "the handler blocked the runtime thread");
+            "test1".to_string()
+        }
+        let (entered, mut entries) = mpsc::unbounded();
+        let (release, gate) = std::sync::mpsc::channel();
+        let client = StateMachineBuilder::new((entered, Arc::new(Mutex::new(gate))))
+            .add_blocking_state("crunch".to_string(), crunch)
+            .add_state("test1".to_string(), || async { "crunch".to_string() })
+            .initial_state("crunch".to_string())
+            .tick_rate(Duration::from_secs(10))
+            .build();
+        client.run().await.unwrap();
+        // The single runtime thread keeps going while the handler blocks
+        entries.next().await.unwrap();
+        assert_eq!(client.get_context().await.current_state, "crunch");
+        release.send(()).unwrap();
+        client
+            .wait_for_state("test1", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
         client.stop().await;
     }
 }
@@ -0,0 +1,559 @@
+//! Core shared by the async and blocking machines
+//!
+//! Everything that does not depend on how a machine waits or runs its handlers lives
+//! here: what the builders collect, the control block with its lifecycle rules, and
+//! the decisions the run loop makes. The async and blocking modules only add the
+//! waiting, spawning and calling on top.
+use event_listener::{Event, EventListener};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[cfg(feature = "checkpoint")]
+use crate::checkpoint::{CheckpointStore, Checkpointer};
+use crate::context::{LifeCycle, StateMachineContext, StopMode};
+use crate::error::Error;
+use crate::event::{EventSender, ExternalEvent, MachineEvent, Subscribers, TransitionEvent};
+use crate::hierarchy::Hooks;
+use crate::history::{self, History};
+#[cfg(feature = "tracing")]
+use crate::instrument;
+#[cfg(feature = "journal")]
+use crate::journal::Journal;
+#[cfg(feature = "serde")]
+use crate::snapshot::MachineSnapshot;
+use crate::state::{Hierarchy, StateName, States};
+use crate::stats::Stats;
+#[cfg(feature = "metrics")]
+use crate::telemetry::Metrics;
+use crate::transition::{Transition, TransitionOutcome};
+
+/// The name of a machine that was not given one
+pub(crate) const DEFAULT_NAME: &str = "state_machine";
+
+/// What every builder collects. `H` is a stored handler and `K` a stored hook.
+pub(crate) struct Parts<H, K, S> {
+    pub(crate) handlers: HashMap<String, H>,
+    pub(crate) hierarchy: Hierarchy,
+    pub(crate) on_stop: Vec<K>,
+    pub(crate) hooks: Hooks<K>,
+    pub(crate) tick_rate: Duration,
+    pub(crate) initial_state: Option<String>,
+    pub(crate) stop_mode: StopMode,
+    /// How many transitions the history remembers
+    pub(crate) history: usize,
+    /// Identifies the machine to metrics, traces and journals
+    pub(crate) name: String,
+    #[cfg(feature = "journal")]
+    pub(crate) journal: Option<Journal>,
+    /// Where to start instead of the initial state
+    #[cfg(feature = "serde")]
+    pub(crate) restored: Option<MachineSnapshot>,
+    #[cfg(feature = "checkpoint")]
+    pub(crate) checkpoint: Option<Checkpointer<S>>,
+    /// Ticks between checkpoints
+    #[cfg(feature = "checkpoint")]
+    pub(crate) checkpoint_every: u64,
+    pub(crate) user_context: S,
+}
+
+impl<H, K, S> Parts<H, K, S> {
+    pub(crate) fn new(user_context: S) -> Self {
+        Self {
+            handlers: HashMap::new(),
+            hierarchy: Hierarchy::default(),
+            on_stop: Vec::new(),
+            hooks: Hooks::default(),
+            tick_rate: Duration::from_millis(50),
+            initial_state: None,
+            stop_mode: StopMode::default(),
+            history: history::DEFAULT_CAPACITY,
+            name: DEFAULT_NAME.to_string(),
+            #[cfg(feature = "journal")]
+            journal: None,
+            #[cfg(feature = "serde")]
+            restored: None,
+            #[cfg(feature = "checkpoint")]
+            checkpoint: None,
+            #[cfg(feature = "checkpoint")]
+            checkpoint_every: 1,
+            user_context,
+        }
+    }
+    /// Checkpoint to `store` under `id`, starting from the checkpoint there if any
+    #[cfg(feature = "checkpoint")]
+    pub(crate) fn resume_from(
+        &mut self,
+        store: std::sync::Arc<dyn CheckpointStore>,
+        id: &str,
+    ) -> Result<(), Error>
+    where
+        S: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let (checkpointer, snapshot) = Checkpointer::resume(store, id)?;
+        if let Some(snapshot) = snapshot {
+            self.restore(snapshot);
+        }
+        self.checkpoint = Some(checkpointer);
+        Ok(())
+    }
+    /// Start from `snapshot`, and with its user context if it has one
+    #[cfg(feature = "serde")]
+    pub(crate) fn restore(&mut self, mut snapshot: MachineSnapshot<S>) {
+        if let Some(user_context) = snapshot.user_context.take() {
+            self.user_context = user_context;
+        }
+        self.restored = Some(snapshot.with_user_context(None));
+    }
+    /// Check what was collected and intern the state names
+    pub(crate) fn into_config(self) -> Config<H, K, S> {
+        if self.handlers.is_empty() {
+            panic!("No states added");
+        }
+        let initial_state = self.initial_state.expect("Initial state not set");
+        let hooks = &self.hooks;
+        let hooked = hooks.enter.keys().chain(hooks.exit.keys());
+        let handlers = States::nested(self.handlers, self.hierarchy, hooked.map(String::as_str));
+        Config {
+            hooks: self.hooks,
+            history: self.history,
+            name: self.name,
+            #[cfg(feature = "journal")]
+            journal: self.journal,
+            #[cfg(feature = "serde")]
+            restored: self.restored,
+            #[cfg(feature = "checkpoint")]
+            checkpoint: self.checkpoint.map(|mut checkpointer| {
+                checkpointer.every = self.checkpoint_every;
+                checkpointer
+            }),
+            ..Config::new(
+                handlers,
+                self.on_stop,
+                self.tick_rate,
+                &initial_state,
+                self.stop_mode,
+                self.user_context,
+            )
+        }
+    }
+}
+
+/// Everything needed to construct a machine
+pub(crate) struct Config<H, K, S> {
+    pub(crate) handlers: States<H>,
+    pub(crate) on_stop: Vec<K>,
+    pub(crate) hooks: Hooks<K>,
+    pub(crate) tick_rate: Duration,
+    pub(crate) initial_state: StateName,
+    pub(crate) stop_mode: StopMode,
+    pub(crate) history: usize,
+    #[cfg_attr(
+        not(any(feature = "metrics", feature = "tracing", feature = "journal")),
+        allow(dead_code)
+    )]
+    pub(crate) name: String,
+    #[cfg(feature = "journal")]
+    pub(crate) journal: Option<Journal>,
+    #[cfg(feature = "serde")]
+    pub(crate) restored: Option<MachineSnapshot>,
+    #[cfg(feature = "checkpoint")]
+    pub(crate) checkpoint: Option<Checkpointer<S>>,
+    pub(crate) user_context: S,
+}
+
+impl<H, K, S> Config<H, K, S> {
+    pub(crate) fn new(
+        handlers: States<H>,
+        on_stop: Vec<K>,
+        tick_rate: Duration,
+        initial_state: &str,
+        stop_mode: StopMode,
+        user_context: S,
+    ) -> Self {
+        Self {
+            initial_state: handlers.enter(initial_state),
+            handlers,
+            on_stop,
+            hooks: Hooks::default(),
+            tick_rate,
+            stop_mode,
+            history: history::DEFAULT_CAPACITY,
+            name: DEFAULT_NAME.to_string(),
+            #[cfg(feature = "journal")]
+            journal: None,
+            #[cfg(feature = "serde")]
+            restored: None,
+            #[cfg(feature = "checkpoint")]
+            checkpoint: None,
+            user_context,
+        }
+    }
+    /// The context of a machine that has not been run yet
+    pub(crate) fn context(&self, life_cycle: LifeCycle) -> StateMachineContext {
+        #[cfg_attr(not(feature = "serde"), allow(unused_mut))]
+        let mut context = StateMachineContext {
+            tick_rate: self.tick_rate,
+            current_state: self.initial_state.clone(),
+            initial_state: self.initial_state.clone(),
+            regions: self.handlers.regions(&self.initial_state),
+            life_cycle,
+            history: History::new(self.history),
+        };
+        #[cfg(feature = "serde")]
+        if let Some(snapshot) = &self.restored {
+            context.current_state = self.handlers.enter(&snapshot.current_state);
+            context.regions = match snapshot.regions.is_empty() {
+                true => self.handlers.regions(&context.current_state),
+                false => snapshot
+                    .regions
+                    .iter()
+                    .map(|r| self.handlers.name(r))
+                    .collect(),
+            };
+            for transition in &snapshot.history {
+                context.history.record(transition.clone());
+            }
+        }
+        context
+    }
+    /// The statistics of a machine that has not been run yet
+    pub(crate) fn stats(&self) -> Stats {
+        #[cfg(feature = "serde")]
+        if let Some(snapshot) = &self.restored {
+            return Stats::resumed(snapshot.ticks);
+        }
+        Stats::default()
+    }
+}
+
+/// Everything guarded by the control lock of a machine. Never held while a handler runs.
+pub(crate) struct Control {
+    /// Shared with the tick in progress, which borrows it instead of a copy
+    pub(crate) context: Arc<StateMachineContext>,
+    /// Whether a run loop is currently driving the context
+    pub(crate) loop_active: bool,
+    /// Whether the first tick of the current run has entered the states it started in
+    pub(crate) entered: bool,
+    /// Mode requested by the most recent stop, applied when the loop exits
+    pub(crate) stop_mode: StopMode,
+    /// Counted over the life of the machine
+    pub(crate) stats: Stats,
+    /// The regions `reset()` returns to along with the initial state
+    initial_regions: Vec<StateName>,
+    /// Published to under this lock, so events arrive in the order things happened
+    events: Subscribers,
+    /// Notified whenever the context changes, for anyone watching it
+    changed: Event,
+    #[cfg(feature = "metrics")]
+    metrics: Metrics,
+    /// Parent of the spans of every handler call
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    /// Every published event is appended to it, under `name`
+    #[cfg(feature = "journal")]
+    journal: Option<(Journal, String)>,
+}
+
+/// What the run loop does next
+pub(crate) enum Next {
+    /// Stop, running the hooks with this context
+    Stop(StateMachineContext),
+    /// Run the handler for the current state
+    Tick,
+    /// Sleep until the next tick is due, or until woken by a control call
+    Until(Instant),
+    /// Paused: sleep until woken by a control call
+    Parked,
+}
+
+impl Control {
+    pub(crate) fn new<H, K, S>(config: &Config<H, K, S>) -> Self {
+        Self {
+            context: Arc::new(config.context(LifeCycle::Stopped)),
+            loop_active: false,
+            entered: false,
+            stop_mode: config.stop_mode,
+            stats: config.stats(),
+            initial_regions: config.handlers.regions(&config.initial_state),
+            events: Subscribers::default(),
+            changed: Event::new(),
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new(&config.name),
+            #[cfg(feature = "tracing")]
+            span: instrument::machine_span(&config.name),
+            #[cfg(feature = "journal")]
+            journal: config
+                .journal
+                .clone()
+                .map(|journal| (journal, config.name.clone())),
+        }
+    }
+    /// The context if `predicate` holds for it, and otherwise a listener notified when
+    /// it next changes. Listening under the lock means no change can be missed.
+    pub(crate) fn watch<P>(&self, predicate: &mut P) -> Result<StateMachineContext, EventListener>
+    where
+        P: FnMut(&StateMachineContext) -> bool,
+    {
+        if predicate(&self.context) {
+            Ok(StateMachineContext::clone(&self.context))
+        } else {
+            Err(self.changed.listen())
+        }
+    }
+    fn notify_changed(&mut self) {
+        #[cfg(feature = "metrics")]
+        self.metrics.context(&self.context);
+        self.changed.notify(usize::MAX);
+    }
+    fn publish(&mut self, event: MachineEvent) {
+        #[cfg(feature = "metrics")]
+        self.metrics.event(&event);
+        #[cfg(feature = "tracing")]
+        instrument::event(&self.span, &event);
+        #[cfg(feature = "journal")]
+        if let Some((journal, name)) = &self.journal {
+            journal.record(name, &self.context.current_state, &event);
+        }
+        self.events.publish(event);
+    }
+    /// Publish an event that happened outside the machine
+    pub(crate) fn external(&mut self, event: ExternalEvent) {
+        self.publish(MachineEvent::External(event));
+    }
+    pub(crate) fn subscribe(&mut self, sender: impl EventSender + 'static) {
+        self.events.add(sender);
+    }
+    /// Decide what the run loop does, given when its next tick is due
+    pub(crate) fn next(&self, next_tick: Instant) -> Next {
+        match self.context.life_cycle {
+            LifeCycle::Stopped => Next::Stop(StateMachineContext::clone(&self.context)),
+            LifeCycle::Paused => Next::Parked,
+            LifeCycle::Running if Instant::now() < next_tick => Next::Until(next_tick),
+            LifeCycle::Running => Next::Tick,
+        }
+    }
+    /// Switch between running and paused. Returns whether anything changed, as a
+    /// stopped machine stays stopped.
+    pub(crate) fn set_life_cycle(&mut self, life_cycle: LifeCycle) -> bool {
+        let event = match (&self.context.life_cycle, &life_cycle) {
+            (LifeCycle::Running, LifeCycle::Paused) => MachineEvent::Paused,
+            (LifeCycle::Paused, LifeCycle::Running) => MachineEvent::Resumed,
+            _ => return false,
+        };
+        self.context_mut().life_cycle = life_cycle;
+        self.publish(event);
+        self.notify_changed();
+        true
+    }
+    /// Tell the run loop to stop with `mode`. Returns whether a loop was active to
+    /// receive the request.
+    pub(crate) fn request_stop(&mut self, mode: StopMode) -> bool {
+        if !self.loop_active {
+            return false;
+        }
+        self.context_mut().life_cycle = LifeCycle::Stopped;
+        self.stop_mode = mode;
+        self.notify_changed();
+        true
+    }
+    /// Claim the run loop for a new run. `Ok(false)` means a stopped loop has not exited
+    /// yet and the caller has to wait for it before trying again.
+    pub(crate) fn claim_loop(&mut self, stop_mode: StopMode) -> Result<bool, Error> {
+        if !self.loop_active {
+            self.loop_active = true;
+            self.entered = false;
+            self.context_mut().life_cycle = LifeCycle::Running;
+            self.stop_mode = stop_mode;
+            self.publish(MachineEvent::Started);
+            self.notify_changed();
+            return Ok(true);
+        }
+        if !matches!(self.context.life_cycle, LifeCycle::Stopped) {
+            return Err(Error::AlreadyRunning);
+        }
+        Ok(false)
+    }
+    /// Mark the loop as exited, however it ended
+    pub(crate) fn release_loop(&mut self) {
+        if std::thread::panicking() {
+            // Unwinding from a handler, so the loop never got to finish its stop
+            let state = self.context.current_state.to_string();
+            self.publish(MachineEvent::Failed(Error::HandlerPanicked(state)));
+            self.publish(MachineEvent::Stopped);
+        }
+        self.context_mut().life_cycle = LifeCycle::Stopped;
+        self.loop_active = false;
+        self.notify_changed();
+    }
+    /// Share the context with a tick, if `allowed` accepts the lifecycle. The tick must
+    /// release it before the context next changes, or the change copies it.
+    pub(crate) fn start_tick(
+        &self,
+        allowed: fn(&LifeCycle) -> bool,
+    ) -> Option<Arc<StateMachineContext>> {
+        allowed(&self.context.life_cycle).then(|| self.context.clone())
+    }
+    /// Where the machine is, without its user context
+    #[cfg(feature = "serde")]
+    pub(crate) fn snapshot<S>(&self) -> MachineSnapshot<S> {
+        MachineSnapshot {
+            current_state: self.context.current_state.to_string(),
+            life_cycle: self.context.life_cycle.clone(),
+            regions: self
+                .context
+                .regions
+                .iter()
+                .map(|state| state.to_string())
+                .collect(),
+            ticks: self.stats.ticks(),
+            history: self.context.history.iter().cloned().collect(),
+            user_context: None,
+        }
+    }
+    #[cfg(feature = "checkpoint")]
+    pub(crate) fn checkpoint_failed(&mut self, error: Error) {
+        self.publish(MachineEvent::CheckpointFailed(error));
+    }
+    /// The span for the handler of the tick about to start
+    #[cfg(feature = "tracing")]
+    pub(crate) fn handler_span(&self) -> tracing::Span {
+        instrument::handler_span(
+            &self.span,
+            &self.context.current_state,
+            self.stats.ticks() + 1,
+        )
+    }
+    /// Move to the state a tick transitioned to, whose handler started at `started`
+    pub(crate) fn finish_tick(&mut self, tick: &Tick, started: Instant) {
+        if let TransitionOutcome::Transitioned(transition) = &tick.outcome {
+            let (context, stats) = (Arc::make_mut(&mut self.context), &mut self.stats);
+            let event = apply(context, stats, transition, &tick.regions, started);
+            self.publish(MachineEvent::Transition(event));
+            self.notify_changed();
+        }
+    }
+    /// Stop the machine from inside its own loop because of `error` and return the
+    /// context to stop with
+    pub(crate) fn fail(&mut self, error: Error) -> StateMachineContext {
+        self.context_mut().life_cycle = LifeCycle::Stopped;
+        self.publish(MachineEvent::Failed(error));
+        self.notify_changed();
+        StateMachineContext::clone(&self.context)
+    }
+    /// The error for a wait that gave up after `timeout`
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn timed_out(&self, timeout: Duration) -> Error {
+        #[cfg(feature = "tracing")]
+        instrument::timed_out(&self.span, timeout);
+        Error::Timeout
+    }
+    /// Apply the requested [`StopMode`] once the hooks have run
+    pub(crate) fn finish_stop(&mut self) {
+        if self.stop_mode == StopMode::Reset {
+            self.reset();
+        }
+        self.stats.leave(Instant::now());
+        self.publish(MachineEvent::Stopped);
+    }
+    /// The context to change, copied first only if a tick still shares it
+    fn context_mut(&mut self) -> &mut StateMachineContext {
+        Arc::make_mut(&mut self.context)
+    }
+    pub(crate) fn reset(&mut self) {
+        let context = Arc::make_mut(&mut self.context);
+        context.current_state = context.initial_state.clone();
+        context.regions = self.initial_regions.clone();
+        self.notify_changed();
+    }
+}
+
+/// Move `context` to the state `transition` went to and the `regions` active in it,
+/// after a handler that started at `started`, and count it in the history and
+/// `stats`. Returns the event describing the tick.
+pub(crate) fn apply(
+    context: &mut StateMachineContext,
+    stats: &mut Stats,
+    transition: &Transition,
+    regions: &[StateName],
+    started: Instant,
+) -> TransitionEvent {
+    let duration = started.elapsed();
+    stats.record(transition, started, duration);
+    let event = TransitionEvent {
+        from: transition.from.clone(),
+        to: transition.to.clone(),
+        tick: stats.ticks(),
+        at: SystemTime::now(),
+        duration_of_handler: duration,
+    };
+    context.current_state = transition.to.clone();
+    context.regions = regions.to_vec();
+    context.history.record(event.clone());
+    event
+}
+
+/// What the handlers of a tick decided
+pub(crate) struct Tick {
+    pub(crate) outcome: TransitionOutcome,
+    /// The state each region is in afterwards, when the machine is in a parallel state
+    pub(crate) regions: Vec<StateName>,
+}
+
+impl Tick {
+    /// The tick reached `state`, which has no handler
+    pub(crate) fn unknown(state: StateName) -> Self {
+        Self {
+            outcome: TransitionOutcome::UnknownState(state),
+            regions: Vec::new(),
+        }
+    }
+    /// A region in `from` returned `to`, which is in its parallel state but not in the
+    /// region
+    pub(crate) fn outside_region(from: StateName, to: StateName) -> Self {
+        Self {
+            outcome: TransitionOutcome::OutsideRegion(Transition { from, to }),
+            regions: Vec::new(),
+        }
+    }
+    /// The tick stayed in parallel `state`, with its regions moved to `regions`
+    pub(crate) fn stay(state: &StateName, regions: Vec<StateName>) -> Self {
+        let transition = Transition {
+            from: state.clone(),
+            to: state.clone(),
+        };
+        Self {
+            outcome: TransitionOutcome::Transitioned(transition),
+            regions,
+        }
+    }
+}
+
+/// The transition a tick made, or the error that keeps the machine from going on
+pub(crate) fn tick_result(outcome: TransitionOutcome) -> Result<Transition, Error> {
+    match outcome {
+        TransitionOutcome::Transitioned(transition) => Ok(transition),
+        TransitionOutcome::UnknownState(state) => Err(Error::UnknownState(state.into())),
+        TransitionOutcome::OutsideRegion(transition) => Err(Error::OutsideRegion {
+            from: transition.from.into(),
+            to: transition.to.into(),
+        }),
+    }
+}
+
+/// What `step()` reports for the outcome of a tick, if one ran
+pub(crate) fn step_result(outcome: Option<TransitionOutcome>) -> Result<Transition, Error> {
+    outcome.map_or(Err(Error::NotPaused), tick_result)
+}
+
+/// Only paused machines can be stepped
+pub(crate) fn is_paused(life_cycle: &LifeCycle) -> bool {
+    matches!(life_cycle, LifeCycle::Paused)
+}
+
+/// The run loop only ticks a running machine
+pub(crate) fn is_running(life_cycle: &LifeCycle) -> bool {
+    matches!(life_cycle, LifeCycle::Running)
+}
@@ -0,0 +1,500 @@
+//! Executors and timers for the async StateMachine
+//!
+//! The run loop only needs two things from an async runtime: a way to spawn itself
+//! ([`Executor`]) and a way to sleep until the next tick ([`Timer`]). Implementations
+//! are provided for tokio (`tokio` feature, enabled by default), smol (`smol` feature)
+//! and async-std (`async-std` feature). Each of them implements both traits.
+//!
+//! The builder picks tokio when its feature is enabled, falling back to smol and then
+//! async-std. Anything else can be selected with `StateMachineBuilder::runtime`:
+//!
+#![cfg_attr(feature = "tokio", doc = "```rust")]
+#![cfg_attr(not(feature = "tokio"), doc = "```rust,ignore")]
+//! use autostatemachine::{runtime::TokioHandle, StateMachineBuilder, StateMachineContext};
+//! async fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let rt = tokio::runtime::Runtime::new().unwrap();
+//! let client = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .runtime(TokioHandle(rt.handle().clone()))
+//!     .build();
+//! // No runtime context needed: the loop is spawned on `rt`
+//! futures::executor::block_on(client.run()).unwrap();
+//! futures::executor::block_on(client.stop());
+//! ```
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use event_listener::{Event, EventListener};
+
+use futures::future::{BoxFuture, LocalBoxFuture};
+
+/// Spawns the run loop of a StateMachine
+pub trait Executor: Send + Sync + 'static {
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+    /// Run a blocking state handler without stalling the runtime. Defaults to a new
+    /// thread per call.
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        std::thread::spawn(task);
+    }
+    /// Whether this can spawn from the calling thread. Checked before the run loop is
+    /// spawned, so starting a machine where it cannot returns an error instead of
+    /// panicking.
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+/// Spawns the run loop of a local StateMachine, which is not `Send`
+pub trait LocalExecutor: 'static {
+    fn spawn_local(&self, future: LocalBoxFuture<'static, ()>);
+}
+
+/// Sleeps between the ticks of a StateMachine
+pub trait Timer: Send + Sync + 'static {
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+    /// Whether this can sleep from the calling thread, like
+    /// [`Executor::is_available`]
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+/// Spawns onto the tokio runtime the machine is started from, with `tokio::spawn`
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Executor for Tokio {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(task);
+    }
+    fn is_available(&self) -> bool {
+        tokio::runtime::Handle::try_current().is_ok()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Timer for Tokio {
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+    fn is_available(&self) -> bool {
+        tokio::runtime::Handle::try_current().is_ok()
+    }
+}
+
+/// Spawns onto a specific tokio runtime, so machines can be started from threads that
+/// are not part of it
+#[cfg(feature = "tokio")]
+#[derive(Clone, Debug)]
+pub struct TokioHandle(pub tokio::runtime::Handle);
+
+#[cfg(feature = "tokio")]
+impl Executor for TokioHandle {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        self.0.spawn(future);
+    }
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        self.0.spawn_blocking(task);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioHandle {
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        let _runtime = self.0.enter();
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// Spawns the run loop of a local machine onto the current `tokio::task::LocalSet`
+/// with `spawn_local`. The machine must be started from inside the `LocalSet`. Only a
+/// [`LocalExecutor`], as `spawn_local` panics for the threaded machines that can be
+/// started from anywhere.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioLocal;
+
+#[cfg(feature = "tokio")]
+impl LocalExecutor for TokioLocal {
+    fn spawn_local(&self, future: LocalBoxFuture<'static, ()>) {
+        tokio::task::spawn_local(future);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioLocal {
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Tokio.sleep_until(deadline)
+    }
+    fn is_available(&self) -> bool {
+        Timer::is_available(&Tokio)
+    }
+}
+
+/// Spawns onto smol's global executor
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Smol;
+
+#[cfg(feature = "smol")]
+impl Executor for Smol {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        smol::spawn(future).detach();
+    }
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        smol::unblock(task).detach();
+    }
+}
+
+#[cfg(feature = "smol")]
+impl Timer for Smol {
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            smol::Timer::at(deadline).await;
+        })
+    }
+}
+
+/// Spawns onto async-std's global executor
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Executor for AsyncStd {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        async_std::task::spawn(future);
+    }
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        async_std::task::spawn_blocking(task);
+    }
+}
+
+#[cfg(feature = "async-std")]
+impl Timer for AsyncStd {
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(
+            deadline.saturating_duration_since(Instant::now()),
+        ))
+    }
+}
+
+/// The executor and timer a machine was built with
+pub(crate) struct Runtime<E: ?Sized = dyn Executor> {
+    pub(crate) executor: Arc<E>,
+    pub(crate) timer: Arc<dyn Timer>,
+}
+
+impl<E: ?Sized> Clone for Runtime<E> {
+    fn clone(&self) -> Self {
+        Self {
+            executor: self.executor.clone(),
+            timer: self.timer.clone(),
+        }
+    }
+}
+
+impl Runtime {
+    #[cfg(any(feature = "tokio", feature = "smol", feature = "async-std"))]
+    pub(crate) fn new<R: Executor + Timer + Clone>(runtime: R) -> Self {
+        Self {
+            executor: Arc::new(runtime.clone()),
+            timer: Arc::new(runtime),
+        }
+    }
+    /// The runtime used when the builder is not told otherwise, if any runtime feature
+    /// is enabled
+    #[allow(unreachable_code)]
+    pub(crate) fn default_for_features() -> Option<Self> {
+        #[cfg(feature = "tokio")]
+        return Some(Self::new(Tokio));
+        #[cfg(feature = "smol")]
+        return Some(Self::new(Smol));
+        #[cfg(feature = "async-std")]
+        return Some(Self::new(AsyncStd));
+        None
+    }
+}
+
+/// A single thread that runs blocking tasks one after another, in the order they were
+/// spawned. Started on first use and stopped once the worker is dropped, after the
+/// tasks already queued.
+#[derive(Default)]
+pub(crate) struct Worker {
+    tasks: OnceLock<mpsc::Sender<Task>>,
+}
+
+type Task = Box<dyn FnOnce() + Send>;
+
+impl Executor for Worker {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        self.spawn_blocking(Box::new(move || futures::executor::block_on(future)));
+    }
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        let tasks = self.tasks.get_or_init(|| {
+            let (tx, rx) = mpsc::channel::<Task>();
+            std::thread::Builder::new()
+                .name("autostatemachine-worker".to_string())
+                .spawn(move || rx.into_iter().for_each(|task| task()))
+                .expect("failed to spawn the worker thread");
+            tx
+        });
+        let _ = tasks.send(task);
+    }
+}
+
+/// Run `f` through [`Executor::spawn_blocking`], resolving to its result. A panic in
+/// `f` is resumed where the result is awaited, as if `f` had been async.
+pub(crate) fn unblock<R: Send + 'static>(
+    executor: &dyn Executor,
+    f: impl FnOnce() -> R + Send + 'static,
+) -> impl futures::Future<Output = R> + Send + 'static {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    executor.spawn_blocking(Box::new(move || {
+        let _ = tx.send(std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)));
+    }));
+    async move {
+        match rx.await {
+            Ok(Ok(result)) => result,
+            Ok(Err(panic)) => std::panic::resume_unwind(panic),
+            Err(_) => panic!("blocking task was dropped before it ran"),
+        }
+    }
+}
+
+/// Sleeps for async callers that have no runtime to take a timer from. One thread,
+/// started on first use, wakes every sleeper of the process at its deadline. Sleeps
+/// dropped before their deadline take their alarm back.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TimerThread;
+
+/// Identifies an alarm, ordered soonest first
+type AlarmKey = (Instant, u64);
+
+enum Alarm {
+    Set(AlarmKey, Event),
+    Cancel(AlarmKey),
+}
+
+/// The alarms set once the timer thread handled its latest message, for tests to
+/// inspect
+#[cfg(test)]
+static PENDING: std::sync::Mutex<Vec<AlarmKey>> = std::sync::Mutex::new(Vec::new());
+
+impl TimerThread {
+    fn alarms() -> &'static mpsc::Sender<Alarm> {
+        static ALARMS: OnceLock<mpsc::Sender<Alarm>> = OnceLock::new();
+        ALARMS.get_or_init(|| {
+            let (tx, rx) = mpsc::channel();
+            std::thread::Builder::new()
+                .name("autostatemachine-timer".to_string())
+                .spawn(move || Self::ring(rx))
+                .expect("failed to spawn the timer thread");
+            tx
+        })
+    }
+    fn ring(rx: mpsc::Receiver<Alarm>) {
+        let mut alarms = BTreeMap::<AlarmKey, Event>::new();
+        loop {
+            let received = match alarms.keys().next() {
+                Some((deadline, _)) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(Alarm::Set(key, event)) => {
+                    alarms.insert(key, event);
+                }
+                Ok(Alarm::Cancel(key)) => {
+                    alarms.remove(&key);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            #[cfg(test)]
+            {
+                *PENDING.lock().unwrap() = alarms.keys().copied().collect();
+            }
+            while let Some(entry) = alarms.first_entry() {
+                if entry.key().0 > Instant::now() {
+                    break;
+                }
+                entry.remove().notify(usize::MAX);
+            }
+        }
+    }
+    /// How many alarms are set for `deadline`, once the timer thread handled every
+    /// alarm sent before
+    #[cfg(all(test, any(feature = "tokio", feature = "smol", feature = "async-std")))]
+    fn count(deadline: Instant) -> usize {
+        // Alarms are handled in order, so one that is already due rings after them
+        futures::executor::block_on(TimerThread.sleep_until(Instant::now()));
+        let pending = PENDING.lock().unwrap();
+        pending.iter().filter(|(at, _)| *at == deadline).count()
+    }
+}
+
+impl Timer for TimerThread {
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let key = (deadline, NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        let event = Event::new();
+        let listener = event.listen();
+        let _ = Self::alarms().send(Alarm::Set(key, event));
+        Box::pin(AlarmSleep { listener, key })
+    }
+}
+
+/// Waits for its alarm to ring, and cancels it if dropped before then
+struct AlarmSleep {
+    listener: EventListener,
+    key: AlarmKey,
+}
+
+impl Future for AlarmSleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.listener).poll(cx)
+    }
+}
+
+impl Drop for AlarmSleep {
+    fn drop(&mut self) {
+        if Instant::now() < self.key.0 {
+            let _ = TimerThread::alarms().send(Alarm::Cancel(self.key));
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "tokio", feature = "smol", feature = "async-std")))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{StateMachineBuilder, StateMachineContext};
+
+    async fn test1(_: StateMachineContext) -> String {
+        "test2".to_string()
+    }
+    async fn test2(_: StateMachineContext) -> String {
+        "test1".to_string()
+    }
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_tokio_local_set() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&rt, async {
+            let client = StateMachineBuilder::new_local(())
+                .add_state("test1".to_string(), test1)
+                .add_state("test2".to_string(), test2)
+                .initial_state("test1".to_string())
+                .runtime(TokioLocal)
+                .build();
+            client.run().await.unwrap();
+            client
+                .wait_for_state("test2", Some(Duration::from_secs(1)))
+                .await
+                .unwrap();
+            client.stop().await;
+        });
+    }
+    #[test]
+    fn test_worker() {
+        let worker = Worker::default();
+        let (tx, rx) = mpsc::channel();
+        for i in 0..3 {
+            let tx = tx.clone();
+            worker.spawn_blocking(Box::new(move || {
+                tx.send((i, std::thread::current().id())).unwrap();
+            }));
+        }
+        let ran: Vec<_> = rx.iter().take(3).collect();
+        assert_eq!(ran.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(ran.iter().all(|(_, thread)| *thread == ran[0].1));
+        assert_ne!(ran[0].1, std::thread::current().id());
+    }
+    #[test]
+    fn test_timer_thread() {
+        let started = Instant::now();
+        let later = TimerThread.sleep_until(started + Duration::from_millis(20));
+        futures::executor::block_on(TimerThread.sleep_until(started + Duration::from_millis(5)));
+        assert!(started.elapsed() < Duration::from_millis(20));
+        futures::executor::block_on(later);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        // Deadlines that already passed wake straight away
+        futures::executor::block_on(TimerThread.sleep_until(started));
+    }
+    #[test]
+    fn test_timer_thread_cancels_dropped_sleeps() {
+        let deadline = Instant::now() + Duration::from_secs(3600);
+        let kept = TimerThread.sleep_until(deadline);
+        drop(TimerThread.sleep_until(deadline));
+        drop(TimerThread.sleep_until(deadline));
+        assert_eq!(TimerThread::count(deadline), 1);
+        drop(kept);
+        assert_eq!(TimerThread::count(deadline), 0);
+    }
+    #[cfg(feature = "smol")]
+    #[test]
+    fn test_smol() {
+        smol::block_on(async {
+            let client = StateMachineBuilder::new(())
+                .add_state("test1".to_string(), test1)
+                .add_state("test2".to_string(), test2)
+                .initial_state("test1".to_string())
+                .runtime(Smol)
+                .build();
+            client.run().await.unwrap();
+            let context = client
+                .wait_until(
+                    |context| context.history.len() == 2,
+                    Some(Duration::from_secs(1)),
+                )
+                .await
+                .unwrap();
+            assert_eq!(context.current_state, "test1");
+            client.stop().await;
+        });
+    }
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_std() {
+        async_std::task::block_on(async {
+            let client = StateMachineBuilder::new(())
+                .add_state("test1".to_string(), test1)
+                .add_state("test2".to_string(), test2)
+                .initial_state("test1".to_string())
+                .runtime(AsyncStd)
+                .build();
+            client.run().await.unwrap();
+            client
+                .wait_for_state("test2", Some(Duration::from_secs(1)))
+                .await
+                .unwrap();
+            client.stop().await;
+        });
+    }
+}
@@ -0,0 +1,103 @@
+//! Snapshot and restore of a StateMachine with serde
+//!
+//! `snapshot()` captures where a machine is: its current state, lifecycle, tick count
+//! and history. `snapshot_with_context()` also clones the user context into it. A
+//! [`MachineSnapshot`] serializes with any serde format, and the builder's `restore()`
+//! rebuilds a machine at that point after a restart.
+//!
+//! A restored machine starts out stopped like any new one, so `life_cycle` only records
+//! what the machine was doing. Its tick numbers and history carry on from the snapshot,
+//! while its statistics start over.
+//!
+//! ```rust
+//! use autostatemachine::{MachineSnapshot, StateMachineBuilder, StateMachineContext};
+//! # async fn run() {
+//! async fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let client = StateMachineBuilder::new(0u32)
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build();
+//! let snapshot = client.snapshot_with_context().await;
+//! // Saved before shutting down, and loaded again after the restart
+//! let restored = StateMachineBuilder::new(0u32)
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .restore(snapshot)
+//!     .build();
+//! # }
+//! ```
+use serde::{Deserialize, Serialize};
+
+use crate::context::LifeCycle;
+use crate::event::TransitionEvent;
+
+/// Where a machine was when the snapshot was taken
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MachineSnapshot<S = ()> {
+    pub current_state: String,
+    /// The state of each region, if `current_state` is a parallel state
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<String>,
+    pub life_cycle: LifeCycle,
+    /// Ticks that ran a handler over the life of the machine
+    pub ticks: u64,
+    /// The remembered transitions, oldest first
+    pub history: Vec<TransitionEvent>,
+    /// Only taken by `snapshot_with_context()`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_context: Option<S>,
+}
+
+impl<S> MachineSnapshot<S> {
+    /// The same snapshot with `user_context` instead. `with_user_context::<()>(None)`
+    /// drops a user context that cannot be serialized, and `with_user_context(None)`
+    /// turns the result back into a snapshot `restore()` accepts.
+    pub fn with_user_context<T>(self, user_context: Option<T>) -> MachineSnapshot<T> {
+        MachineSnapshot {
+            current_state: self.current_state,
+            regions: self.regions,
+            life_cycle: self.life_cycle,
+            ticks: self.ticks,
+            history: self.history,
+            user_context,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateName;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = MachineSnapshot {
+            current_state: "b".to_string(),
+            regions: Vec::new(),
+            life_cycle: LifeCycle::Paused,
+            ticks: 1,
+            history: vec![TransitionEvent {
+                from: StateName::from("a"),
+                to: StateName::from("b"),
+                tick: 1,
+                at: SystemTime::UNIX_EPOCH,
+                duration_of_handler: Duration::from_millis(5),
+            }],
+            user_context: Some(7u32),
+        };
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<MachineSnapshot<u32>>(&json).unwrap(),
+            snapshot
+        );
+        let json = serde_json::to_string(&snapshot.clone().with_user_context::<()>(None)).unwrap();
+        assert!(!json.contains("user_context"));
+        assert_eq!(
+            serde_json::from_str::<MachineSnapshot<u32>>(&json).unwrap(),
+            snapshot.with_user_context(None)
+        );
+    }
+}
@@ -0,0 +1,541 @@
+//! Interned state names
+//!
+//! State names are interned when a machine is built. Every registered state gets a
+//! [`StateId`] indexing its handler, and the context refers to states by [`StateName`],
+//! which carries the id next to a shared copy of the name. Cloning the context or moving
+//! to another state never allocates, while the name stays available for display and
+//! comparison.
+//!
+//! Handlers return a [`Target`], through anything that converts into one. A `String`
+//! or `&'static str` is looked up by name, while a [`StateName`] or [`StateId`] handed
+//! out by the machine is used as is, so a handler returning one neither allocates nor
+//! hashes.
+//!
+//! Names of states inside compound states also carry their parents, so
+//! [`StateName::path`] lists every state that is active, from the outermost compound
+//! state down to the one whose name it is.
+//!
+//! ```rust
+//! use autostatemachine::{StateMachineBuilder, StateMachineContext, StateName};
+//! # async fn run() {
+//! async fn idle(context: StateMachineContext) -> StateName {
+//!     assert_eq!(context.current_state, "idle");
+//!     println!("in {} ({:?})", context.current_state, context.current_state.id());
+//!     context.current_state
+//! }
+//! let mut machine = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build_step_machine();
+//! machine.step().await;
+//! # }
+//! ```
+use std::{
+    borrow::{Borrow, Cow},
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    sync::Arc,
+};
+
+/// Index of a state registered with a machine. Only meaningful for the machine that
+/// assigned it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StateId(u32);
+
+impl StateId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// The name of a state, shared rather than copied
+///
+/// Names of registered states carry their [`StateId`]. A name a handler returned without
+/// registering it has no id, and the machine stops when it reaches it. Names compare
+/// and hash like the `str` they hold.
+#[derive(Clone)]
+pub struct StateName {
+    id: Option<StateId>,
+    name: Arc<str>,
+    /// The compound states containing this one, outermost first
+    parents: Option<Arc<[StateName]>>,
+}
+
+impl StateName {
+    /// The id of the state, if it was registered with the machine
+    pub fn id(&self) -> Option<StateId> {
+        self.id
+    }
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+    /// The compound states containing this one, outermost first
+    pub fn parents(&self) -> &[StateName] {
+        self.parents.as_deref().unwrap_or_default()
+    }
+    /// The compound states containing this one and then this one, which is every state
+    /// active while this one is
+    pub fn path(&self) -> impl Iterator<Item = &StateName> {
+        self.parents().iter().chain(std::iter::once(self))
+    }
+    /// Whether this is `state` or one of its children
+    pub fn is_in(&self, state: &str) -> bool {
+        self.path().any(|active| active == state)
+    }
+}
+
+impl Deref for StateName {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl AsRef<str> for StateName {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Borrow<str> for StateName {
+    fn borrow(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Display for StateName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl fmt::Debug for StateName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.name, f)
+    }
+}
+
+impl PartialEq for StateName {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.name, &other.name) || self.name == other.name
+    }
+}
+
+impl Eq for StateName {}
+
+impl Hash for StateName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state)
+    }
+}
+
+impl PartialEq<str> for StateName {
+    fn eq(&self, other: &str) -> bool {
+        &*self.name == other
+    }
+}
+
+impl PartialEq<&str> for StateName {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.name == *other
+    }
+}
+
+impl PartialEq<String> for StateName {
+    fn eq(&self, other: &String) -> bool {
+        *self.name == **other
+    }
+}
+
+impl From<&str> for StateName {
+    fn from(name: &str) -> Self {
+        Self {
+            id: None,
+            name: name.into(),
+            parents: None,
+        }
+    }
+}
+
+impl From<String> for StateName {
+    fn from(name: String) -> Self {
+        Self {
+            id: None,
+            name: name.into(),
+            parents: None,
+        }
+    }
+}
+
+/// Serialized as the name alone. Deserialized names carry no id.
+#[cfg(feature = "serde")]
+impl serde::Serialize for StateName {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for StateName {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+impl From<StateName> for String {
+    fn from(name: StateName) -> Self {
+        name.name.to_string()
+    }
+}
+
+/// Where a handler sends the machine
+///
+/// Handlers return anything that converts into a target: the name of a state as a
+/// `String` or `&'static str`, or a [`StateName`] or [`StateId`] of the machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target(Kind);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    Name(Cow<'static, str>),
+    State(StateName),
+    Id(StateId),
+}
+
+impl Target {
+    /// Whether this targets `state` itself
+    pub(crate) fn is(&self, state: &StateName) -> bool {
+        match &self.0 {
+            Kind::Name(name) => state == &**name,
+            Kind::State(name) => name == state,
+            Kind::Id(id) => state.id == Some(*id),
+        }
+    }
+}
+
+impl From<String> for Target {
+    fn from(name: String) -> Self {
+        Self(Kind::Name(name.into()))
+    }
+}
+
+impl From<&'static str> for Target {
+    fn from(name: &'static str) -> Self {
+        Self(Kind::Name(name.into()))
+    }
+}
+
+impl From<StateName> for Target {
+    fn from(state: StateName) -> Self {
+        Self(Kind::State(state))
+    }
+}
+
+impl From<&StateName> for Target {
+    fn from(state: &StateName) -> Self {
+        Self(Kind::State(state.clone()))
+    }
+}
+
+impl From<StateId> for Target {
+    fn from(id: StateId) -> Self {
+        Self(Kind::Id(id))
+    }
+}
+
+/// A state containing others, as declared on the builder
+pub(crate) struct Compound {
+    /// The child entered along with the compound state
+    pub(crate) initial: String,
+    pub(crate) children: Vec<String>,
+}
+
+/// A state whose regions are all active at once, as declared on the builder
+#[derive(Default)]
+pub(crate) struct Parallel {
+    /// Compound states, each entered at its initial child
+    pub(crate) regions: Vec<String>,
+    /// Where to go once every region is in a final state
+    pub(crate) join: Option<String>,
+}
+
+/// How the states of a machine contain each other, as declared on the builder
+#[derive(Default)]
+pub(crate) struct Hierarchy {
+    pub(crate) compound: HashMap<String, Compound>,
+    pub(crate) parallel: HashMap<String, Parallel>,
+    pub(crate) finals: HashSet<String>,
+}
+
+/// What a state contains
+#[derive(Clone, Default)]
+struct Node {
+    /// The initial child of a compound state
+    initial: Option<StateId>,
+    /// The regions of a parallel state
+    regions: Vec<StateId>,
+    /// Where a parallel state goes once its regions are final
+    join: Option<StateId>,
+    is_final: bool,
+}
+
+/// Handlers indexed by [`StateId`], and the names they were registered under
+pub(crate) struct States<H> {
+    /// `None` for states containing others that were given no handler
+    handlers: Vec<Option<H>>,
+    names: Vec<StateName>,
+    ids: HashMap<Arc<str>, StateId>,
+    nodes: Vec<Node>,
+}
+
+impl<H> States<H> {
+    /// Intern the names of `handlers`, assigning ids in name order so they do not depend
+    /// on hashing
+    pub(crate) fn new(handlers: HashMap<String, H>) -> Self {
+        Self::nested(handlers, Hierarchy::default(), [])
+    }
+    /// Intern the names of `handlers` and of the states containing them. Panics if the
+    /// `hierarchy` is not a tree of registered states, or if a state that was given
+    /// hooks in `hooked` is not a state.
+    pub(crate) fn nested<'a>(
+        handlers: HashMap<String, H>,
+        hierarchy: Hierarchy,
+        hooked: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let Hierarchy {
+            compound,
+            parallel,
+            finals,
+        } = hierarchy;
+        let registered = |state: &str| handlers.contains_key(state) || compound.contains_key(state);
+        let mut parents = HashMap::new();
+        let mut contain = |parent: &str, child: &str| {
+            if let Some(other) = parents.insert(child.to_string(), parent.to_string()) {
+                panic!("State {child} is a child of both {other} and {parent}");
+            }
+        };
+        for (parent, Compound { initial, children }) in &compound {
+            if !children.contains(initial) {
+                panic!("Initial child {initial} is not a child of {parent}");
+            }
+            for child in children {
+                if !registered(child) && !parallel.contains_key(child) {
+                    panic!("Child state {child} of {parent} has no handler");
+                }
+                contain(parent, child);
+            }
+        }
+        for (name, Parallel { regions, join }) in &parallel {
+            if regions.is_empty() {
+                panic!("Parallel state {name} has no regions");
+            }
+            for region in regions {
+                if !compound.contains_key(region) {
+                    panic!("Region {region} of {name} is not a compound state");
+                }
+                contain(name, region);
+            }
+            if let Some(join) = join.as_ref().filter(|join| !registered(join)) {
+                panic!("Parallel state {name} joins {join}, which is not a state");
+            }
+        }
+        if let Some(state) = finals.iter().find(|state| !handlers.contains_key(*state)) {
+            panic!("Final state {state} has no handler");
+        }
+        let mut hooked = hooked.into_iter();
+        if let Some(state) =
+            hooked.find(|state| !registered(state) && !parallel.contains_key(*state))
+        {
+            panic!("Hooks were added to {state}, which is not a state");
+        }
+        let mut ids: Vec<String> = handlers
+            .keys()
+            .chain(compound.keys())
+            .chain(parallel.keys())
+            .cloned()
+            .collect();
+        ids.sort();
+        ids.dedup();
+        let id = |name: &str| {
+            let index = ids.binary_search_by(|id| id.as_str().cmp(name)).unwrap();
+            StateId(index as u32)
+        };
+        // Parents are interned before their children, so children can share them
+        let mut order: Vec<(usize, &str)> = ids
+            .iter()
+            .map(|name| {
+                let mut depth = 0;
+                let mut state = name.as_str();
+                while let Some(parent) = parents.get(state) {
+                    if parent == name || depth == ids.len() {
+                        panic!("Compound state {name} contains itself");
+                    }
+                    depth += 1;
+                    state = parent;
+                }
+                (depth, name.as_str())
+            })
+            .collect();
+        order.sort();
+        // Regions keep a single active state each, so they cannot fork again
+        for name in parallel.keys() {
+            let mut state = name.as_str();
+            while let Some(parent) = parents.get(state) {
+                if parallel.contains_key(parent) {
+                    panic!("Parallel state {name} is inside a region of {parent}");
+                }
+                state = parent;
+            }
+        }
+        let mut names: Vec<Option<StateName>> = vec![None; ids.len()];
+        for (_, name) in order {
+            let parents = parents.get(name).map(|parent| {
+                let parent = names[id(parent).index()].as_ref().unwrap();
+                parent.path().cloned().collect::<Arc<[StateName]>>()
+            });
+            names[id(name).index()] = Some(StateName {
+                id: Some(id(name)),
+                name: name.into(),
+                parents,
+            });
+        }
+        let nodes = ids
+            .iter()
+            .map(|name| Node {
+                initial: compound.get(name).map(|c| id(&c.initial)),
+                regions: parallel
+                    .get(name)
+                    .map(|p| p.regions.iter().map(|region| id(region)).collect())
+                    .unwrap_or_default(),
+                join: parallel.get(name).and_then(|p| p.join.as_deref()).map(id),
+                is_final: finals.contains(name),
+            })
+            .collect();
+        let mut handlers = handlers;
+        let handlers = ids.iter().map(|name| handlers.remove(name)).collect();
+        let names: Vec<StateName> = names.into_iter().map(Option::unwrap).collect();
+        Self {
+            handlers,
+            ids: names
+                .iter()
+                .map(|name| (name.name.clone(), name.id.unwrap()))
+                .collect(),
+            names,
+            nodes,
+        }
+    }
+    /// The interned name for `name`, or an unregistered one if no state has it
+    pub(crate) fn name(&self, name: &str) -> StateName {
+        match self.ids.get(name) {
+            Some(id) => self.names[id.index()].clone(),
+            None => name.into(),
+        }
+    }
+    /// The state reached by entering `name`: `name` itself, or the initial child of a
+    /// compound state, all the way down
+    pub(crate) fn enter(&self, name: &str) -> StateName {
+        self.descend(self.name(name))
+    }
+    /// The state reached by entering `target`, found by name only if it is one
+    pub(crate) fn target(&self, target: Target) -> StateName {
+        let state = match target.0 {
+            Kind::Name(name) => self.name(&name),
+            Kind::State(state) => match self.id(&state) {
+                Some(id) => self.names[id.index()].clone(),
+                None => state,
+            },
+            Kind::Id(id) => match self.names.get(id.index()) {
+                Some(name) => name.clone(),
+                None => format!("{id:?}").into(),
+            },
+        };
+        self.descend(state)
+    }
+    /// The initial child of `state` if it is a compound state, all the way down
+    fn descend(&self, mut state: StateName) -> StateName {
+        while let Some(child) = self.node(&state).and_then(|node| node.initial) {
+            state = self.names[child.index()].clone();
+        }
+        state
+    }
+    /// Whether `state` is a compound or parallel state
+    pub(crate) fn has_children(&self, state: &StateName) -> bool {
+        self.node(state)
+            .is_some_and(|node| node.initial.is_some() || !node.regions.is_empty())
+    }
+    /// The states the regions of `state` start in, or none if it is not parallel
+    pub(crate) fn regions(&self, state: &StateName) -> Vec<StateName> {
+        match self.node(state) {
+            Some(node) => node
+                .regions
+                .iter()
+                .map(|region| self.descend(self.names[region.index()].clone()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Where parallel `state` goes now that its regions are in `regions`, if they are
+    /// all final
+    pub(crate) fn join(&self, state: &StateName, regions: &[StateName]) -> Option<StateName> {
+        let join = self.node(state)?.join?;
+        let finished = regions
+            .iter()
+            .all(|region| self.node(region).is_some_and(|node| node.is_final));
+        finished.then(|| self.descend(self.names[join.index()].clone()))
+    }
+    fn node(&self, state: &StateName) -> Option<&Node> {
+        self.nodes.get(self.id(state)?.index())
+    }
+    /// The id of `state`, checked when it was interned by this table and looked up by
+    /// name otherwise
+    fn id(&self, state: &StateName) -> Option<StateId> {
+        match state.id {
+            Some(id)
+                if self
+                    .names
+                    .get(id.index())
+                    .is_some_and(|name| Arc::ptr_eq(&name.name, &state.name)) =>
+            {
+                Some(id)
+            }
+            _ => self.ids.get(state.as_str()).copied(),
+        }
+    }
+    /// The handler of `state`, found by its id when it was interned by this table and by
+    /// name otherwise
+    pub(crate) fn handler(&self, state: &StateName) -> Option<&H> {
+        self.handlers.get(self.id(state)?.index())?.as_ref()
+    }
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.handlers.iter().flatten().count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interning() {
+        let states = States::new(HashMap::from([("b".to_string(), 2), ("a".to_string(), 1)]));
+        assert_eq!(states.len(), 2);
+        let a = states.name("a");
+        assert_eq!(a.id().map(StateId::index), Some(0));
+        assert_eq!(a, "a");
+        assert_eq!(states.handler(&a), Some(&1));
+        // Names built elsewhere are looked up by name
+        assert_eq!(states.handler(&"b".into()), Some(&2));
+        let missing = states.name("missing");
+        assert_eq!(missing.id(), None);
+        assert_eq!(states.handler(&missing), None);
+        // Handlers can target a state by name, by its interned name or by its id
+        assert_eq!(states.target("b".into()), "b");
+        let target = states.target(a.clone().into());
+        assert!(Arc::ptr_eq(&target.name, &a.name));
+        assert_eq!(states.target(StateId(1).into()), "b");
+        assert_eq!(states.target(missing.into()).id(), None);
+    }
+}
@@ -0,0 +1,301 @@
+//! Per-state statistics of a StateMachine
+//!
+//! Every machine counts its ticks as they happen: how often each state was entered,
+//! how long the machine stayed in it, how long its handler takes, and how often each
+//! transition was made. `get_stats()` returns a [`MachineStats`] snapshot, including
+//! the time spent so far in the current state.
+//!
+//! ```rust
+//! use autostatemachine::{StateMachineBuilder, StateMachineContext};
+//! # async fn run() {
+//! async fn idle(_: StateMachineContext) -> String {
+//!     "idle".to_string()
+//! }
+//! let mut machine = StateMachineBuilder::new(())
+//!     .add_state("idle".to_string(), idle)
+//!     .initial_state("idle".to_string())
+//!     .build_step_machine();
+//! machine.step().await;
+//! let stats = machine.get_stats();
+//! let idle = stats.state("idle").unwrap();
+//! println!(
+//!     "{} ticks, p99 handler latency under {:?}",
+//!     idle.ticks,
+//!     idle.handler_latency.quantile(0.99)
+//! );
+//! assert_eq!(stats.transitions("idle", "idle"), 1);
+//! # }
+//! ```
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use crate::state::StateName;
+use crate::transition::Transition;
+
+/// Buckets of the latency histogram. Bucket `i` holds latencies below `2^i` µs, and
+/// the last one everything longer.
+const BUCKETS: usize = 26;
+
+/// Distribution of handler latencies, in power-of-two buckets from 1µs to about 17s
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; BUCKETS],
+    total: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_micros();
+        let bucket = (u128::BITS - micros.leading_zeros()) as usize;
+        self.counts[bucket.min(BUCKETS - 1)] += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+    /// How many latencies were recorded
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+    pub fn mean(&self) -> Option<Duration> {
+        let count = self.count();
+        (count > 0).then(|| Duration::from_nanos((self.total.as_nanos() / count as u128) as u64))
+    }
+    /// The upper bound of every bucket with how many latencies fell below it. The last
+    /// bound is `Duration::MAX`.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts.iter().enumerate().map(|(i, &count)| {
+            let bound = if i == BUCKETS - 1 {
+                Duration::MAX
+            } else {
+                Duration::from_micros(1 << i)
+            };
+            (bound, count)
+        })
+    }
+    /// An upper bound for the `q` quantile (between 0 and 1), accurate to the bucket it
+    /// falls in and never above the longest latency recorded
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets()
+            .find(|(_, n)| {
+                seen += n;
+                seen >= rank
+            })
+            .map(|(bound, _)| bound.min(self.max))
+    }
+}
+
+/// What happened in one state
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateStats {
+    /// How often the machine entered the state. Staying in it does not count.
+    pub visits: u64,
+    /// How often its handler ran
+    pub ticks: u64,
+    /// Time spent in the state over every visit, paused or not
+    pub total_time: Duration,
+    /// The longest single visit
+    pub max_time: Duration,
+    pub handler_latency: LatencyHistogram,
+}
+
+/// Snapshot of the statistics of a machine
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MachineStats {
+    /// Ticks that ran a handler
+    pub ticks: u64,
+    pub states: HashMap<StateName, StateStats>,
+    /// How often each `(from, to)` transition was made
+    pub transitions: HashMap<(StateName, StateName), u64>,
+}
+
+impl MachineStats {
+    pub fn state(&self, name: &str) -> Option<&StateStats> {
+        self.states.get(name)
+    }
+    /// How often the machine went from `from` to `to`
+    pub fn transitions(&self, from: &str, to: &str) -> u64 {
+        let key: &dyn TransitionKey = &(from, to);
+        self.transitions.get(key).copied().unwrap_or(0)
+    }
+}
+
+/// A `(from, to)` transition, so the `(StateName, StateName)` keys of
+/// [`MachineStats::transitions`] can be looked up with a pair of `&str`
+trait TransitionKey {
+    fn names(&self) -> (&str, &str);
+}
+
+impl TransitionKey for (StateName, StateName) {
+    fn names(&self) -> (&str, &str) {
+        (self.0.borrow(), self.1.borrow())
+    }
+}
+
+impl TransitionKey for (&str, &str) {
+    fn names(&self) -> (&str, &str) {
+        *self
+    }
+}
+
+impl<'a> Borrow<dyn TransitionKey + 'a> for (StateName, StateName) {
+    fn borrow(&self) -> &(dyn TransitionKey + 'a) {
+        self
+    }
+}
+
+impl PartialEq for dyn TransitionKey + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.names() == other.names()
+    }
+}
+
+impl Eq for dyn TransitionKey + '_ {}
+
+// Hashes like the tuple of `StateName`s, which hash as their names
+impl Hash for dyn TransitionKey + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.names().hash(state)
+    }
+}
+
+/// Keeps the statistics of a machine up to date as it ticks
+#[derive(Default)]
+pub(crate) struct Stats {
+    stats: MachineStats,
+    /// The state being visited and since when
+    visit: Option<(StateName, Instant)>,
+}
+
+impl Stats {
+    /// Statistics of a restored machine, whose tick numbers carry on from `ticks`
+    #[cfg(feature = "serde")]
+    pub(crate) fn resumed(ticks: u64) -> Self {
+        let mut stats = Self::default();
+        stats.stats.ticks = ticks;
+        stats
+    }
+    pub(crate) fn ticks(&self) -> u64 {
+        self.stats.ticks
+    }
+    /// Count a tick whose handler started at `started`, took `duration` and made
+    /// `transition`
+    pub(crate) fn record(&mut self, transition: &Transition, started: Instant, duration: Duration) {
+        if self
+            .visit
+            .as_ref()
+            .is_none_or(|(state, _)| *state != transition.from)
+        {
+            self.leave(started);
+            self.enter(&transition.from, started);
+        }
+        self.stats.ticks += 1;
+        let state = self
+            .stats
+            .states
+            .entry(transition.from.clone())
+            .or_default();
+        state.ticks += 1;
+        state.handler_latency.record(duration);
+        *self
+            .stats
+            .transitions
+            .entry((transition.from.clone(), transition.to.clone()))
+            .or_default() += 1;
+        if transition.from != transition.to {
+            let now = started + duration;
+            self.leave(now);
+            self.enter(&transition.to, now);
+        }
+    }
+    /// End the current visit, as when the machine is stopped
+    pub(crate) fn leave(&mut self, now: Instant) {
+        if let Some((state, since)) = self.visit.take() {
+            add_visit(self.stats.states.entry(state).or_default(), now - since);
+        }
+    }
+    fn enter(&mut self, state: &StateName, now: Instant) {
+        self.stats.states.entry(state.clone()).or_default().visits += 1;
+        self.visit = Some((state.clone(), now));
+    }
+    /// The statistics so far, counting the current visit up to now
+    pub(crate) fn snapshot(&self) -> MachineStats {
+        let mut stats = self.stats.clone();
+        if let Some((state, since)) = &self.visit {
+            add_visit(
+                stats.states.entry(state.clone()).or_default(),
+                since.elapsed(),
+            );
+        }
+        stats
+    }
+}
+
+fn add_visit(state: &mut StateStats, time: Duration) {
+    state.total_time += time;
+    state.max_time = state.max_time.max(time);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transition(from: &str, to: &str) -> Transition {
+        Transition {
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+    #[test]
+    fn test_record() {
+        let mut stats = Stats::default();
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        stats.record(&transition("a", "a"), start, ms(1));
+        stats.record(&transition("a", "b"), start + ms(10), ms(2));
+        stats.record(&transition("b", "a"), start + ms(20), ms(3));
+        stats.leave(start + ms(30));
+        let stats = stats.snapshot();
+        assert_eq!(stats.ticks, 3);
+        assert_eq!(stats.transitions("a", "b"), 1);
+        assert_eq!(stats.transitions("b", "b"), 0);
+        let a = stats.state("a").unwrap();
+        assert_eq!((a.visits, a.ticks), (2, 2));
+        // 12ms on the first visit and 7ms on the second
+        assert_eq!(a.total_time, ms(19));
+        assert_eq!(a.max_time, ms(12));
+        assert_eq!(a.handler_latency.count(), 2);
+        assert_eq!(a.handler_latency.max(), ms(2));
+        assert_eq!(stats.state("b").unwrap().total_time, ms(11));
+    }
+    #[test]
+    fn test_histogram() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        for micros in [0, 3, 3, 100, 5000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(4)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(5000)));
+        assert_eq!(
+            histogram.buckets().next(),
+            Some((Duration::from_micros(1), 1))
+        );
+    }
+}
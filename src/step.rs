@@ -0,0 +1,301 @@
+//! Manually stepped StateMachine
+//!
+//! A [`StepMachine`] runs exactly one handler each time [`StepMachine::step`] is awaited.
+//! It never spawns a task or sleeps, so it can be driven from an existing frame or event
+//! loop that decides when ticks happen. The run loop behind `StateMachine::run` drives
+//! its ticks through the same core.
+//!
+//! ```rust
+//! use autostatemachine::{StateMachineBuilder, StateMachineContext, TransitionOutcome};
+//! # async fn run() {
+//! async fn ping(_: StateMachineContext) -> String {
+//!     "pong".to_string()
+//! }
+//! async fn pong(_: StateMachineContext) -> String {
+//!     "ping".to_string()
+//! }
+//! let mut machine = StateMachineBuilder::new(())
+//!     .add_state("ping".to_string(), ping)
+//!     .add_state("pong".to_string(), pong)
+//!     .initial_state("ping".to_string())
+//!     .build_step_machine();
+//! // Called once per frame
+//! if let TransitionOutcome::Transitioned(t) = machine.step().await {
+//!     println!("{} -> {}", t.from, t.to);
+//! }
+//! # }
+//! ```
+#[cfg(feature = "journal")]
+use futures::FutureExt;
+#[cfg(feature = "journal")]
+use std::{io::BufRead, panic::AssertUnwindSafe};
+use std::{slice, time::Instant};
+
+use crate::context::{LifeCycle, StateMachineContext};
+#[cfg(feature = "journal")]
+use crate::error::Error;
+#[cfg(feature = "journal")]
+use crate::event::ExternalEvent;
+use crate::flavor::{Flavor, Threaded};
+use crate::handle::MachineConfig;
+use crate::hierarchy::{self, Decision, Hooks};
+#[cfg(feature = "journal")]
+use crate::journal::{self, Divergence, Replay, ReplayReport};
+use crate::machine::{self, Tick};
+use crate::state::{StateName, States, Target};
+use crate::stats::{MachineStats, Stats};
+use crate::transition::TransitionOutcome;
+
+/// Run the handlers along `path`, outermost first, until one decides where to go
+async fn call_path<'a, S, F: Flavor<S>>(
+    handlers: &States<Box<F::Callback<Target>>>,
+    path: impl Iterator<Item = &'a StateName>,
+    context: &StateMachineContext,
+    user_context: &mut S,
+) -> Decision {
+    for state in path {
+        let parent = handlers.has_children(state);
+        let to = match handlers.handler(state) {
+            Some(handler) => F::call(handler, context, user_context).await,
+            None if parent => continue,
+            None => return Decision::Unknown(state.clone()),
+        };
+        // A state with children returning itself leaves the tick to them
+        if !(parent && to.is(state)) {
+            return Decision::To(handlers.target(to));
+        }
+    }
+    Decision::Deferred
+}
+
+/// Run the handlers active in `context` and the hooks of the transition they make,
+/// and report where they went, without applying it to `context`. The handlers
+/// borrow both contexts until they resolve.
+pub(crate) async fn call_handler<S, F: Flavor<S>>(
+    handlers: &States<Box<F::Callback<Target>>>,
+    hooks: &Hooks<Box<F::Callback<()>>>,
+    context: &StateMachineContext,
+    user_context: &mut S,
+) -> Tick {
+    let state = &context.current_state;
+    let to = match call_path::<S, F>(handlers, state.path(), context, user_context).await {
+        Decision::To(to) => Some(to),
+        Decision::Unknown(state) => return Tick::unknown(state),
+        Decision::Deferred => None,
+    };
+    let mut regions = context.regions.clone();
+    // Left to a parallel state, whose regions each tick in turn
+    let to = match to {
+        Some(to) => to,
+        None => {
+            let mut left = None;
+            for region in regions.iter_mut() {
+                let below = region.path().skip(state.parents().len() + 1);
+                match call_path::<S, F>(handlers, below, context, user_context).await {
+                    Decision::To(to) if hierarchy::in_region(state, region, &to) => *region = to,
+                    Decision::To(to) if to.is_in(state) => {
+                        return Tick::outside_region(region.clone(), to)
+                    }
+                    Decision::To(to) => {
+                        left = Some(to);
+                        break;
+                    }
+                    Decision::Unknown(state) => return Tick::unknown(state),
+                    Decision::Deferred => {}
+                }
+            }
+            // Every region has resolved, so the moves are final
+            for (from, to) in context.regions.iter().zip(&regions) {
+                for hook in hooks.crossed(slice::from_ref(from), slice::from_ref(to)) {
+                    F::call(hook, context, user_context).await;
+                }
+            }
+            match left.or_else(|| handlers.join(state, &regions)) {
+                Some(to) => to,
+                None => return Tick::stay(state, regions),
+            }
+        }
+    };
+    let (tick, crossed) = hierarchy::transition(handlers, hooks, state, &regions, to);
+    for hook in crossed {
+        F::call(hook, context, user_context).await;
+    }
+    tick
+}
+
+/// Run the entry hooks of the states active in `context`, as the machine starts in them
+pub(crate) async fn call_entered<S, F: Flavor<S>>(
+    hooks: &Hooks<Box<F::Callback<()>>>,
+    context: &StateMachineContext,
+    user_context: &mut S,
+) {
+    let active = hierarchy::active(&context.current_state, &context.regions);
+    for hook in hooks.entered(active) {
+        F::call(hook, context, user_context).await;
+    }
+}
+
+/// Run the exit hooks of the states active in `context`, as the machine stops in them
+pub(crate) async fn call_exited<S, F: Flavor<S>>(
+    hooks: &Hooks<Box<F::Callback<()>>>,
+    context: &StateMachineContext,
+    user_context: &mut S,
+) {
+    let active = hierarchy::active(&context.current_state, &context.regions);
+    for hook in hooks.exited(active) {
+        F::call(hook, context, user_context).await;
+    }
+}
+
+/// A StateMachine that only ticks when told to
+pub struct StepMachine<S, F: Flavor<S> = Threaded> {
+    handlers: States<Box<F::Callback<Target>>>,
+    hooks: Hooks<Box<F::Callback<()>>>,
+    context: StateMachineContext,
+    user_context: S,
+    stats: Stats,
+    /// Whether the states the machine started in have been entered, which the first
+    /// step does
+    entered: bool,
+    /// Whose entries `replay()` reads
+    #[cfg(feature = "journal")]
+    name: String,
+}
+
+impl<S, F> StepMachine<S, F>
+where
+    F: Flavor<S>,
+{
+    pub(crate) fn new(config: MachineConfig<S, F>) -> Self {
+        Self {
+            context: config.context(LifeCycle::Running),
+            stats: config.stats(),
+            entered: false,
+            #[cfg(feature = "journal")]
+            name: config.name,
+            handlers: config.handlers,
+            hooks: config.hooks,
+            user_context: config.user_context,
+        }
+    }
+    pub fn get_context(&self) -> &StateMachineContext {
+        &self.context
+    }
+    pub fn get_stats(&self) -> MachineStats {
+        self.stats.snapshot()
+    }
+    pub fn get_user_context(&self) -> &S {
+        &self.user_context
+    }
+    pub fn get_user_context_mut(&mut self) -> &mut S {
+        &mut self.user_context
+    }
+    /// Run the handler for the current state once and move to the state it returns.
+    /// The first step enters the states the machine starts in before that.
+    pub async fn step(&mut self) -> TransitionOutcome {
+        if !std::mem::replace(&mut self.entered, true) {
+            call_entered::<S, F>(&self.hooks, &self.context, &mut self.user_context).await;
+        }
+        let started = Instant::now();
+        let tick = call_handler::<S, F>(
+            &self.handlers,
+            &self.hooks,
+            &self.context,
+            &mut self.user_context,
+        )
+        .await;
+        if let TransitionOutcome::Transitioned(transition) = &tick.outcome {
+            let (context, stats) = (&mut self.context, &mut self.stats);
+            machine::apply(context, stats, transition, &tick.regions, started);
+        }
+        tick.outcome
+    }
+    /// Step through the ticks recorded in `journal` for the machine of the same name,
+    /// handing its external events to `feed` to apply to the user context. Stops at the
+    /// first tick that does not do what it did when recorded. See the
+    /// [`journal`](crate::journal) module.
+    ///
+    /// Returns [`Error::Journal`] if `journal` cannot be read or parsed.
+    #[cfg(feature = "journal")]
+    pub async fn replay(
+        &mut self,
+        journal: impl BufRead,
+        mut feed: impl FnMut(&ExternalEvent, &mut S),
+    ) -> Result<ReplayReport, Error> {
+        let mut ticks = 0;
+        let name = self.name.clone();
+        for entry in journal::entries(journal, &name) {
+            let (line, recorded) = entry?;
+            match Replay::of(&recorded.event) {
+                Replay::Tick => {
+                    ticks += 1;
+                    // A recorded panic is reproduced by panicking again
+                    let outcome = AssertUnwindSafe(self.step()).catch_unwind().await.ok();
+                    if !journal::reproduces(&recorded.event, outcome.as_ref()) {
+                        let divergence = Divergence {
+                            line,
+                            recorded,
+                            replayed: outcome,
+                        };
+                        return Ok(ReplayReport {
+                            ticks,
+                            divergence: Some(divergence),
+                        });
+                    }
+                }
+                Replay::Feed(event) => feed(event, &mut self.user_context),
+                Replay::Restart => {
+                    // The recorded machine stopped in between, leaving its states
+                    if std::mem::replace(&mut self.entered, false) {
+                        call_exited::<S, F>(&self.hooks, &self.context, &mut self.user_context)
+                            .await;
+                    }
+                    let state = self.handlers.name(&recorded.state);
+                    self.context.regions = self.handlers.regions(&state);
+                    self.context.current_state = state;
+                }
+                Replay::Skip => {}
+            }
+        }
+        Ok(ReplayReport {
+            ticks,
+            divergence: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::StateMachineBuilder;
+    use crate::transition::Transition;
+
+    async fn test1(_: StateMachineContext) -> String {
+        "test2".to_string()
+    }
+    async fn test2(_: StateMachineContext) -> String {
+        "missing".to_string()
+    }
+    #[test]
+    fn test_step_without_runtime() {
+        let mut machine = StateMachineBuilder::new(())
+            .add_state("test1".to_string(), test1)
+            .add_state("test2".to_string(), test2)
+            .initial_state("test1".to_string())
+            .build_step_machine();
+        let outcome = futures::executor::block_on(machine.step());
+        assert_eq!(
+            outcome,
+            TransitionOutcome::Transitioned(Transition {
+                from: "test1".into(),
+                to: "test2".into()
+            })
+        );
+        futures::executor::block_on(machine.step());
+        assert_eq!(machine.get_context().current_state, "missing");
+        assert_eq!(
+            futures::executor::block_on(machine.step()),
+            TransitionOutcome::UnknownState("missing".into())
+        );
+    }
+}
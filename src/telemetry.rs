@@ -0,0 +1,193 @@
+//! Metrics of every StateMachine, through the [`metrics`] facade
+//!
+//! With the `metrics` feature enabled, each machine reports what it does to whichever
+//! recorder is installed, such as a Prometheus exporter, without any change to its
+//! handlers. Every metric carries a `machine` label with the name given to the builder.
+//!
+//! | Metric | Kind | Labels |
+//! | --- | --- | --- |
+//! | [`TRANSITIONS_TOTAL`] | counter | `machine`, `from`, `to` |
+//! | [`HANDLER_DURATION_SECONDS`] | histogram | `machine`, `state` |
+//! | [`CURRENT_STATE`] | gauge, 1 for the current state and 0 for the others | `machine`, `state` |
+//! | [`LIFE_CYCLE`] | gauge, 1 for the current lifecycle and 0 for the others | `machine`, `life_cycle` |
+//!
+//! Machines built with `build_step_machine()` have no run loop and report nothing.
+use ::metrics::{counter, gauge, histogram, SharedString};
+
+use crate::context::{LifeCycle, StateMachineContext};
+use crate::event::MachineEvent;
+use crate::state::StateName;
+
+/// Ticks that ran a handler, by the transition they made
+pub const TRANSITIONS_TOTAL: &str = "state_machine_transitions_total";
+/// How long handlers took, by the state they ran for
+pub const HANDLER_DURATION_SECONDS: &str = "state_machine_handler_duration_seconds";
+/// Which state the machine is in
+pub const CURRENT_STATE: &str = "state_machine_current_state";
+/// Whether the machine is running, paused or stopped
+pub const LIFE_CYCLE: &str = "state_machine_life_cycle";
+
+fn life_cycle_label(life_cycle: &LifeCycle) -> &'static str {
+    match life_cycle {
+        LifeCycle::Running => "running",
+        LifeCycle::Paused => "paused",
+        LifeCycle::Stopped => "stopped",
+    }
+}
+
+/// Reports the events and context of one machine to the installed recorder
+pub(crate) struct Metrics {
+    machine: SharedString,
+    /// What the gauges were last set to, so only changes are reported
+    state: Option<StateName>,
+    life_cycle: Option<&'static str>,
+}
+
+impl Metrics {
+    pub(crate) fn new(machine: &str) -> Self {
+        Self {
+            machine: SharedString::from(machine.to_string()),
+            state: None,
+            life_cycle: None,
+        }
+    }
+    pub(crate) fn event(&self, event: &MachineEvent) {
+        if let MachineEvent::Transition(t) = event {
+            counter!(
+                TRANSITIONS_TOTAL,
+                "machine" => self.machine.clone(),
+                "from" => t.from.to_string(),
+                "to" => t.to.to_string()
+            )
+            .increment(1);
+            histogram!(
+                HANDLER_DURATION_SECONDS,
+                "machine" => self.machine.clone(),
+                "state" => t.from.to_string()
+            )
+            .record(t.duration_of_handler.as_secs_f64());
+        }
+    }
+    /// Bring the gauges in line with `context`
+    pub(crate) fn context(&mut self, context: &StateMachineContext) {
+        if self.state.as_ref() != Some(&context.current_state) {
+            if let Some(old) = self.state.replace(context.current_state.clone()) {
+                self.state_gauge(&old, 0.0);
+            }
+            self.state_gauge(&context.current_state, 1.0);
+        }
+        let life_cycle = life_cycle_label(&context.life_cycle);
+        if self.life_cycle != Some(life_cycle) {
+            if let Some(old) = self.life_cycle.replace(life_cycle) {
+                self.life_cycle_gauge(old, 0.0);
+            }
+            self.life_cycle_gauge(life_cycle, 1.0);
+        }
+    }
+    fn state_gauge(&self, state: &StateName, value: f64) {
+        gauge!(
+            CURRENT_STATE,
+            "machine" => self.machine.clone(),
+            "state" => state.to_string()
+        )
+        .set(value);
+    }
+    fn life_cycle_gauge(&self, life_cycle: &'static str, value: f64) {
+        gauge!(
+            LIFE_CYCLE,
+            "machine" => self.machine.clone(),
+            "life_cycle" => life_cycle
+        )
+        .set(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::StopMode;
+    use crate::machine::{Config, Control, Tick};
+    use crate::transition::{Transition, TransitionOutcome};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
+
+    fn config() -> Config<(), (), ()> {
+        let handlers = HashMap::from([("a".to_string(), ()), ("b".to_string(), ())]);
+        Config {
+            name: "orders".to_string(),
+            ..Config::new(
+                crate::state::States::new(handlers),
+                Vec::new(),
+                Duration::from_millis(1),
+                "a",
+                StopMode::Preserve,
+                (),
+            )
+        }
+    }
+    #[test]
+    fn test_recorded_metrics() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        ::metrics::with_local_recorder(&recorder, || {
+            let mut control = Control::new(&config());
+            control.claim_loop(StopMode::Preserve).unwrap();
+            let transition = Transition {
+                from: "a".into(),
+                to: "b".into(),
+            };
+            let tick = Tick {
+                outcome: TransitionOutcome::Transitioned(transition),
+                regions: Vec::new(),
+            };
+            control.finish_tick(&tick, Instant::now());
+            control.set_life_cycle(LifeCycle::Paused);
+        });
+        let metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels: Vec<_> = key
+                    .labels()
+                    .map(|l| format!("{}={}", l.key(), l.value()))
+                    .collect();
+                (key.name().to_string(), labels.join(","), value)
+            })
+            .collect();
+        let value = |name: &str, labels: &str| {
+            metrics
+                .iter()
+                .find(|(n, l, _)| n == name && l == labels)
+                .map(|(_, _, value)| value)
+        };
+        assert_eq!(
+            value(TRANSITIONS_TOTAL, "machine=orders,from=a,to=b"),
+            Some(&DebugValue::Counter(1))
+        );
+        assert!(matches!(
+            value(HANDLER_DURATION_SECONDS, "machine=orders,state=a"),
+            Some(DebugValue::Histogram(samples)) if samples.len() == 1
+        ));
+        assert_eq!(
+            value(CURRENT_STATE, "machine=orders,state=a"),
+            Some(&DebugValue::Gauge(0.0.into()))
+        );
+        assert_eq!(
+            value(CURRENT_STATE, "machine=orders,state=b"),
+            Some(&DebugValue::Gauge(1.0.into()))
+        );
+        assert_eq!(
+            value(LIFE_CYCLE, "machine=orders,life_cycle=running"),
+            Some(&DebugValue::Gauge(0.0.into()))
+        );
+        assert_eq!(
+            value(LIFE_CYCLE, "machine=orders,life_cycle=paused"),
+            Some(&DebugValue::Gauge(1.0.into()))
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! Transitions made by a StateMachine
+//!
+//! Every handler invocation produces a [`Transition`] from the state it ran in to the
+//! state it returned, even when the two are the same. Asking for a tick yields a
+//! [`TransitionOutcome`], which also covers a current state that has no handler and a
+//! region of a parallel state returning a state outside itself.
+use crate::state::StateName;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub from: StateName,
+    pub to: StateName,
+}
+
+/// The result of asking a machine to run one tick
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransitionOutcome {
+    /// The handler for the current state ran and returned the next state
+    Transitioned(Transition),
+    /// The current state has no handler, so nothing ran
+    UnknownState(StateName),
+    /// A handler in a region of a parallel state returned another state of the parallel
+    /// state outside its region, so nothing moved
+    OutsideRegion(Transition),
+}
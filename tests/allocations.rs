@@ -0,0 +1,53 @@
+//! Counts the allocations of a tick, which needs a global allocator of its own
+use autostatemachine::blocking::{StateMachineBuilder, StateMachineContext, StateName};
+use autostatemachine::TransitionOutcome;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[test]
+fn test_ticks_do_not_allocate() {
+    // One handler returns a static name, the other a name the machine handed out
+    let mut machine = StateMachineBuilder::new(None::<StateName>)
+        .add_state("ping".to_string(), |_: StateMachineContext| "pong")
+        .add_state(
+            "pong".to_string(),
+            |_: &StateMachineContext, ping: &mut Option<StateName>| ping.clone().unwrap(),
+        )
+        .initial_state("ping".to_string())
+        .history(4)
+        .build_step_machine();
+    let ping = machine.get_context().current_state.clone();
+    *machine.get_user_context_mut() = Some(ping);
+    // Statistics and history fill up on the first transitions
+    for _ in 0..8 {
+        machine.step();
+    }
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..100 {
+        assert!(matches!(machine.step(), TransitionOutcome::Transitioned(_)));
+    }
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed) - before, 0);
+}